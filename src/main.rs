@@ -1,20 +1,48 @@
-use clap::{Arg, Command};
+use clap::parser::ValueSource;
+use clap::{Arg, ArgGroup, Command};
+use rand::rngs::StdRng;
 use rand::seq::SliceRandom;
-use rand::thread_rng;
+use rand::{Rng, SeedableRng, thread_rng};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::{File, write};
-use std::io::{BufReader, BufWriter};
+use std::io::{BufRead, BufWriter, Write};
 use std::path::Path;
 use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 const ALPHABET: &str = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ ";
 const DEFAULT_ROTOR_FILE: &str = "./daily_key.enigma";
 const DEFAULT_PLUGBOARD_FILE: &str = "./plugboard.toml";
+const DEFAULT_NOTCHES: [usize; 3] = [16, 4, 21];
+const EQUIVALENCE_SAMPLE_PRESSES: usize = 4 * ALPHABET.len() * ALPHABET.len();
+const MAX_MIN_DISPLACEMENT: u64 = 5;
+const PLUGBOARD_HEADER: &str = r#"
+# Enigma Plugboard Configuration
+# Each pair swaps two characters bidirectionally
+# Use two-character strings like "ab", "CD", "X ", etc.
+"#;
+const MORSE_GROUP_SIZE: usize = 5;
+const MORSE_WORD_SEPARATOR: char = '/';
+const MAX_PLUG_SOLUTIONS: usize = 20;
+const EXPORT_VECTOR_COUNT: usize = 32;
+const SPELLING_ESCAPE: char = 'Q';
+const DIGIT_NAMES: [&str; 10] = [
+    "ZERO", "ONE", "TWO", "THREE", "FOUR", "FIVE", "SIX", "SEVEN", "EIGHT", "NINE",
+];
+
+/// Exit code after an interrupt, following the shell's 128 + SIGINT.
+const INTERRUPTED_EXIT_CODE: i32 = 130;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
 
 #[derive(Debug)]
 enum EnigmaError {
     InvalidRotorPosition(char),
+    InvalidRotorWiring(String),
+    InvalidReflectorPosition(char),
+    InvalidReflectorWiring(String),
+    InvalidNotchPosition(usize),
     InvalidMessage(String),
     InvalidPlugboardPair(String),
     FileError(String),
@@ -25,6 +53,19 @@ impl std::fmt::Display for EnigmaError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             EnigmaError::InvalidRotorPosition(c) => write!(f, "Invalid rotor position: {}", c),
+            EnigmaError::InvalidRotorWiring(msg) => write!(f, "Invalid rotor wiring: {}", msg),
+            EnigmaError::InvalidReflectorPosition(c) => {
+                write!(f, "Invalid reflector position: {}", c)
+            }
+            EnigmaError::InvalidReflectorWiring(msg) => {
+                write!(f, "Invalid reflector wiring: {}", msg)
+            }
+            EnigmaError::InvalidNotchPosition(pos) => write!(
+                f,
+                "Invalid notch position: {} (must be below {})",
+                pos,
+                ALPHABET.len()
+            ),
             EnigmaError::InvalidMessage(msg) => write!(f, "Invalid message: {}", msg),
             EnigmaError::InvalidPlugboardPair(pair) => {
                 write!(f, "Invalid plugboard pair: {}", pair)
@@ -55,11 +96,52 @@ impl From<toml::de::Error> for EnigmaError {
     }
 }
 
+impl From<toml::ser::Error> for EnigmaError {
+    fn from(err: toml::ser::Error) -> Self {
+        EnigmaError::SerializationError(err.to_string())
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 struct RotorState {
     rotor1: String,
     rotor2: String,
     rotor3: String,
+    /// A generated reflector wiring. Files without one use `Reflector::new`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reflector: Option<String>,
+}
+
+impl RotorState {
+    fn validate(&self) -> Result<(), EnigmaError> {
+        validate_wiring("rotor1", &self.rotor1)?;
+        validate_wiring("rotor2", &self.rotor2)?;
+        validate_wiring("rotor3", &self.rotor3)?;
+        if let Some(reflector) = &self.reflector {
+            Reflector::from_wiring(reflector)?;
+        }
+        Ok(())
+    }
+}
+
+fn validate_wiring(name: &str, wiring: &str) -> Result<(), EnigmaError> {
+    if wiring.chars().count() != ALPHABET.len() {
+        return Err(EnigmaError::InvalidRotorWiring(format!(
+            "{} has {} characters, expected {}",
+            name,
+            wiring.chars().count(),
+            ALPHABET.len()
+        )));
+    }
+
+    if let Some(missing) = ALPHABET.chars().find(|&c| !wiring.contains(c)) {
+        return Err(EnigmaError::InvalidRotorWiring(format!(
+            "{} is missing '{}'",
+            name, missing
+        )));
+    }
+
+    Ok(())
 }
 
 #[derive(Deserialize)]
@@ -67,6 +149,68 @@ struct PlugboardConfig {
     pairs: Vec<String>,
 }
 
+impl PlugboardConfig {
+    /// Renders the config in the same layout as the `generate_plugboard`
+    /// template, one pair per line.
+    fn to_toml(&self) -> String {
+        let pairs: String = self
+            .pairs
+            .iter()
+            .map(|pair| format!("    \"{}\",\n", pair))
+            .collect();
+
+        format!("{}\npairs = [\n{}]\n", PLUGBOARD_HEADER, pairs)
+    }
+}
+
+/// The optional notch letters of a `.toml` rotor file, read separately so the
+/// bincode layout of `RotorState` stays unchanged.
+#[derive(Deserialize)]
+struct RotorNotches {
+    notches: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct MachineConfig {
+    rotor1: String,
+    rotor2: String,
+    rotor3: String,
+    notches: Option<String>,
+    reflector: Option<String>,
+    reflector_position: Option<char>,
+    #[serde(default)]
+    pairs: Vec<String>,
+}
+
+/// A snapshot of the settings that change between messages, as opposed to
+/// the wirings in `RotorState`.
+#[derive(Serialize, Deserialize)]
+struct MachineState {
+    positions: String,
+    notches: String,
+    reflector_position: char,
+    pairs: Vec<String>,
+}
+
+/// Where the signal passes through the plugboard. `Both` is the historical
+/// wiring. `EntryOnly` emulates machines that only plug the keyboard side;
+/// it is not reciprocal, and `ExitOnly` is its inverse, used to decrypt.
+#[derive(Clone, Copy, PartialEq)]
+enum PlugboardMode {
+    Both,
+    EntryOnly,
+    ExitOnly,
+}
+
+/// Which rotors moved on a single keypress.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct StepInfo {
+    rotor1: bool,
+    rotor2: bool,
+    rotor3: bool,
+}
+
+#[derive(Clone)]
 struct Rotor {
     wiring: String,
     position: usize,
@@ -74,12 +218,14 @@ struct Rotor {
 }
 
 impl Rotor {
-    fn new(wiring: String, notch_position: usize) -> Self {
-        Self {
+    fn new(wiring: String, notch_position: usize) -> Result<Self, EnigmaError> {
+        let mut rotor = Self {
             wiring,
             position: 0,
-            notch_position,
-        }
+            notch_position: 0,
+        };
+        rotor.set_notch(notch_position)?;
+        Ok(rotor)
     }
 
     fn set_position(&mut self, pos: char) -> Result<(), EnigmaError> {
@@ -90,10 +236,34 @@ impl Rotor {
         Ok(())
     }
 
+    fn set_notch(&mut self, notch_position: usize) -> Result<(), EnigmaError> {
+        if notch_position >= ALPHABET.len() {
+            return Err(EnigmaError::InvalidNotchPosition(notch_position));
+        }
+
+        self.notch_position = notch_position;
+        Ok(())
+    }
+
+    fn position_char(&self) -> char {
+        ALPHABET.chars().nth(self.position).unwrap()
+    }
+
     fn at_notch(&self) -> bool {
         self.position == self.notch_position
     }
 
+    /// Lists every position in a full revolution at which `at_notch` holds.
+    fn notch_positions_in_cycle(&self) -> Vec<usize> {
+        let mut rotor = self.clone();
+        (0..ALPHABET.len())
+            .filter(|&position| {
+                rotor.position = position;
+                rotor.at_notch()
+            })
+            .collect()
+    }
+
     fn step(&mut self) {
         self.position = (self.position + 1) % ALPHABET.len();
     }
@@ -113,59 +283,142 @@ impl Rotor {
     }
 }
 
+#[derive(Clone)]
 struct Reflector {
     wiring: String,
+    position: usize,
 }
 
 impl Reflector {
+    /// Pairs neighbouring alphabet characters (`a`-`b`, `c`-`d`, ...). The
+    /// alphabet has an odd length, so its last character (space) has no
+    /// partner and is reflected onto itself.
     fn new() -> Self {
-        let alphabet_len = ALPHABET.len();
-        let mut wiring = vec!['\0'; alphabet_len];
-
-        let alphabet_chars: Vec<char> = ALPHABET.chars().collect();
-        let mut used = vec![false; alphabet_len];
-
-        for i in 0..alphabet_len {
-            if used[i] {
-                continue;
-            }
-
-            let mut pair_found = false;
-            for j in (i + 1)..alphabet_len {
-                if !used[j] {
-                    wiring[i] = alphabet_chars[j];
-                    wiring[j] = alphabet_chars[i];
-                    used[i] = true;
-                    used[j] = true;
-                    pair_found = true;
-                    break;
-                }
-            }
+        Self::with_fixed_point(' ').unwrap()
+    }
 
-            if !pair_found && alphabet_len % 2 == 1 && i == alphabet_len - 1 {
-                for j in 0..i {
-                    if wiring[j] == alphabet_chars[i] {
-                        let old_pair = ALPHABET.find(wiring[j]).unwrap();
-                        wiring[i] = alphabet_chars[old_pair];
-                        wiring[old_pair] = alphabet_chars[i];
-                        wiring[j] = alphabet_chars[i];
-                        break;
-                    }
-                }
-            }
+    /// Pairs neighbouring alphabet characters as `new` does, but skips
+    /// `fixed`, which becomes the one character reflected onto itself.
+    fn with_fixed_point(fixed: char) -> Result<Self, EnigmaError> {
+        if !ALPHABET.contains(fixed) {
+            return Err(EnigmaError::InvalidReflectorWiring(format!(
+                "fixed point '{}' is not in the alphabet",
+                fixed
+            )));
         }
 
-        Self {
+        let mut wiring: Vec<char> = ALPHABET.chars().collect();
+        let others: Vec<char> = ALPHABET.chars().filter(|&c| c != fixed).collect();
+
+        for pair in others.chunks_exact(2) {
+            wiring[ALPHABET.find(pair[0]).unwrap()] = pair[1];
+            wiring[ALPHABET.find(pair[1]).unwrap()] = pair[0];
+        }
+
+        Ok(Self {
             wiring: wiring.into_iter().collect(),
+            position: 0,
+        })
+    }
+
+    /// Builds a reflector from swap pairs. A reflector must pair every
+    /// character, but the alphabet's odd length leaves exactly one of them
+    /// unpaired, reflected onto itself.
+    fn from_pairs(pairs: Vec<String>) -> Result<Self, EnigmaError> {
+        let expected = ALPHABET.len() / 2;
+        if pairs.len() != expected {
+            return Err(EnigmaError::InvalidReflectorWiring(format!(
+                "expected {} pairs, found {}",
+                expected,
+                pairs.len()
+            )));
+        }
+
+        let plugboard = Plugboard::from_pairs(pairs)
+            .map_err(|e| EnigmaError::InvalidReflectorWiring(e.to_string()))?;
+
+        Ok(Self {
+            wiring: ALPHABET.chars().map(|c| plugboard.swap(c)).collect(),
+            position: 0,
+        })
+    }
+
+    /// Checks that `wiring` is a reflector, pairing every character but one,
+    /// and builds it.
+    fn from_wiring(wiring: &str) -> Result<Self, EnigmaError> {
+        validate_wiring("reflector", wiring)
+            .map_err(|e| EnigmaError::InvalidReflectorWiring(e.to_string()))?;
+
+        let mut pairs = Vec::new();
+        for (i, (a, b)) in ALPHABET.chars().zip(wiring.chars()).enumerate() {
+            if wiring.chars().nth(ALPHABET.find(b).unwrap()) != Some(a) {
+                return Err(EnigmaError::InvalidReflectorWiring(format!(
+                    "'{}' is wired to '{}', but not back",
+                    a, b
+                )));
+            }
+            if i < ALPHABET.find(b).unwrap() {
+                pairs.push([a, b].iter().collect());
+            }
         }
+
+        Self::from_pairs(pairs)
+    }
+
+    /// Pairs the alphabet at random, leaving one random character unpaired.
+    fn random<R: Rng + ?Sized>(rng: &mut R) -> Self {
+        Self::from_pairs(random_pairs(rng, ALPHABET.len() / 2)).unwrap()
+    }
+
+    fn set_position(&mut self, pos: char) -> Result<(), EnigmaError> {
+        let position = ALPHABET
+            .find(pos)
+            .ok_or(EnigmaError::InvalidReflectorPosition(pos))?;
+        self.position = position;
+        Ok(())
     }
 
     fn reflect(&self, input: usize) -> usize {
-        let reflected_char = self.wiring.chars().nth(input).unwrap();
-        ALPHABET.find(reflected_char).unwrap()
+        let offset = (input + self.position) % ALPHABET.len();
+        let reflected_char = self.wiring.chars().nth(offset).unwrap();
+        let reflected_pos = ALPHABET.find(reflected_char).unwrap();
+        (reflected_pos + ALPHABET.len() - self.position) % ALPHABET.len()
+    }
+}
+
+/// The entry wheel (Eintrittswalze) sits between the plugboard and the fast
+/// rotor. The signal crosses it forward on the way in and backward on the
+/// way out, so any permutation keeps the machine reciprocal.
+#[derive(Clone)]
+struct EntryWheel {
+    wiring: String,
+}
+
+impl EntryWheel {
+    fn new() -> Self {
+        Self {
+            wiring: ALPHABET.to_string(),
+        }
+    }
+
+    fn from_wiring(wiring: &str) -> Result<Self, EnigmaError> {
+        let wiring = parse_wiring(wiring)?;
+        validate_wiring("entry wheel", &wiring)?;
+        Ok(Self { wiring })
+    }
+
+    fn forward(&self, input: usize) -> usize {
+        let wired_char = self.wiring.chars().nth(input).unwrap();
+        ALPHABET.find(wired_char).unwrap()
+    }
+
+    fn backward(&self, input: usize) -> usize {
+        let input_char = ALPHABET.chars().nth(input).unwrap();
+        self.wiring.find(input_char).unwrap()
     }
 }
 
+#[derive(Clone)]
 struct Plugboard {
     mapping: HashMap<char, char>,
 }
@@ -206,69 +459,305 @@ impl Plugboard {
         Ok(Self { mapping })
     }
 
+    /// Combines plugboards loaded from several files into one. A character
+    /// plugged in more than one file is an error naming both files.
+    fn merge(sources: &[(&str, Plugboard)]) -> Result<Self, EnigmaError> {
+        let mut merged = Self::new();
+        let mut owners: HashMap<char, &str> = HashMap::new();
+
+        for (source, plugboard) in sources {
+            for (a, b) in plugboard.pairs() {
+                for c in [a, b] {
+                    if let Some(first) = owners.get(&c) {
+                        return Err(EnigmaError::InvalidPlugboardPair(format!(
+                            "'{}' is plugged in both {} and {}",
+                            c, first, source
+                        )));
+                    }
+                }
+
+                owners.insert(a, source);
+                owners.insert(b, source);
+                merged.mapping.insert(a, b);
+                merged.mapping.insert(b, a);
+            }
+        }
+
+        Ok(merged)
+    }
+
+    /// Returns every swapped pair once, ordered by the first character's
+    /// position in the alphabet.
+    fn pairs(&self) -> Vec<(char, char)> {
+        ALPHABET
+            .chars()
+            .filter_map(|a| {
+                let b = self.swap(a);
+                let a_first = ALPHABET.find(a).unwrap() < ALPHABET.find(b).unwrap();
+                a_first.then_some((a, b))
+            })
+            .collect()
+    }
+
+    fn to_config(&self) -> PlugboardConfig {
+        PlugboardConfig {
+            pairs: self
+                .pairs()
+                .iter()
+                .map(|&(a, b)| [a, b].iter().collect())
+                .collect(),
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.mapping.iter().any(|(a, b)| a != b)
+    }
+
     fn swap(&self, c: char) -> char {
         *self.mapping.get(&c).unwrap_or(&c)
     }
 }
 
+#[derive(Clone)]
 struct EnigmaMachine {
     rotor1: Rotor,
     rotor2: Rotor,
     rotor3: Rotor,
     reflector: Reflector,
+    entry_wheel: EntryWheel,
     plugboard: Plugboard,
+    switched_plugboard: Option<(Plugboard, usize)>,
+    plugboard_mode: PlugboardMode,
+    /// When set, the first keypress after `set_positions` does not step, to
+    /// match implementations that step after encoding rather than before.
+    skip_first_step: bool,
+    pressed_since_set: bool,
 }
 
 impl EnigmaMachine {
     fn new(
         rotor_file: &str,
-        plugboard_file: Option<&str>,
+        plugboard_files: &[&str],
         positions: &str,
     ) -> Result<Self, EnigmaError> {
-        if !Path::new(rotor_file).exists() {
-            return Err(EnigmaError::FileError(format!(
-                "Rotor file '{}' not found",
-                rotor_file
-            )));
+        let rotor_state = Self::load_rotor_state(rotor_file)?;
+        let notches = Self::load_rotor_notches(rotor_file)?.unwrap_or(DEFAULT_NOTCHES);
+        let plugboard = Self::load_optional_plugboard(plugboard_files)?;
+
+        Self::from_parts(rotor_state, notches, plugboard, positions)
+    }
+
+    fn from_config(
+        config_file: &str,
+        rotor_file: Option<&str>,
+        plugboard_files: &[&str],
+        positions: &str,
+    ) -> Result<Self, EnigmaError> {
+        let contents = std::fs::read_to_string(config_file)?;
+        let config: MachineConfig = toml::from_str(&contents)?;
+
+        let rotor_state = match rotor_file {
+            Some(rotor_file) => Self::load_rotor_state(rotor_file)?,
+            None => {
+                let rotor_state = RotorState {
+                    rotor1: parse_wiring(&config.rotor1)?,
+                    rotor2: parse_wiring(&config.rotor2)?,
+                    rotor3: parse_wiring(&config.rotor3)?,
+                    reflector: config.reflector.as_deref().map(parse_wiring).transpose()?,
+                };
+                rotor_state.validate()?;
+                rotor_state
+            }
+        };
+
+        let plugboard = match plugboard_files {
+            [] => Plugboard::from_pairs(config.pairs)?,
+            _ => Self::load_optional_plugboard(plugboard_files)?,
+        };
+
+        let rotor_file_notches = match rotor_file {
+            Some(rotor_file) => Self::load_rotor_notches(rotor_file)?,
+            None => None,
+        };
+        let notches = match (rotor_file_notches, config.notches) {
+            (Some(notches), _) => notches,
+            (None, Some(notches)) => parse_notches(&notches)?,
+            (None, None) => DEFAULT_NOTCHES,
+        };
+
+        let mut machine = Self::from_parts(rotor_state, notches, plugboard, positions)?;
+        if let Some(reflector_position) = config.reflector_position {
+            machine.reflector.set_position(reflector_position)?;
         }
 
-        let file = File::open(rotor_file)?;
-        let reader = BufReader::new(file);
-        let rotor_state: RotorState = bincode::deserialize_from(reader)?;
+        Ok(machine)
+    }
+
+    fn from_parts(
+        rotor_state: RotorState,
+        notches: [usize; 3],
+        plugboard: Plugboard,
+        positions: &str,
+    ) -> Result<Self, EnigmaError> {
+        let mut machine = Self {
+            rotor1: Rotor::new(rotor_state.rotor1, notches[0])?,
+            rotor2: Rotor::new(rotor_state.rotor2, notches[1])?,
+            rotor3: Rotor::new(rotor_state.rotor3, notches[2])?,
+            reflector: match &rotor_state.reflector {
+                Some(wiring) => Reflector::from_wiring(wiring)?,
+                None => Reflector::new(),
+            },
+            entry_wheel: EntryWheel::new(),
+            plugboard,
+            switched_plugboard: None,
+            plugboard_mode: PlugboardMode::Both,
+            skip_first_step: false,
+            pressed_since_set: false,
+        };
+        machine.set_positions(positions)?;
+
+        Ok(machine)
+    }
+
+    /// Returns a copy of this machine reset to `start`. The copy keeps every
+    /// other setting, and `self` is left untouched.
+    fn at_positions(&self, start: &str) -> Result<EnigmaMachine, EnigmaError> {
+        let mut machine = self.clone();
+        machine.set_positions(start)?;
+        Ok(machine)
+    }
+
+    /// Returns a copy of this machine reset to `start`, ready to decrypt a
+    /// message that was encrypted from those start positions. A one-sided
+    /// plugboard moves to the other side, since that mode is not reciprocal.
+    fn decryptor(&self, start: &str) -> Result<EnigmaMachine, EnigmaError> {
+        let mut machine = self.at_positions(start)?;
+        machine.plugboard_mode = match self.plugboard_mode {
+            PlugboardMode::Both => PlugboardMode::Both,
+            PlugboardMode::EntryOnly => PlugboardMode::ExitOnly,
+            PlugboardMode::ExitOnly => PlugboardMode::EntryOnly,
+        };
+        Ok(machine)
+    }
+
+    /// Encrypts `plaintext`, then decrypts the result from the same start
+    /// positions. Returns the index of the first character where the
+    /// decryption differs from `plaintext`, or `None` if it matches.
+    fn check_roundtrip(&mut self, plaintext: &str) -> Result<Option<usize>, EnigmaError> {
+        let start = self.positions();
+        let ciphertext = self.encode_message(plaintext)?;
+        let decrypted = self.decryptor(&start)?.encode_message(&ciphertext)?;
 
-        let mut rotor1 = Rotor::new(rotor_state.rotor1, 16);
-        let mut rotor2 = Rotor::new(rotor_state.rotor2, 4);
-        let mut rotor3 = Rotor::new(rotor_state.rotor3, 21);
+        if decrypted == plaintext {
+            return Ok(None);
+        }
+        Ok(Some(
+            plaintext
+                .chars()
+                .zip(decrypted.chars())
+                .position(|(a, b)| a != b)
+                .unwrap_or(plaintext.chars().count().min(decrypted.chars().count())),
+        ))
+    }
 
+    fn set_positions(&mut self, positions: &str) -> Result<(), EnigmaError> {
         if positions.len() != 3 {
             return Err(EnigmaError::InvalidMessage(
                 "Rotor positions must be 3 characters".to_string(),
             ));
         }
 
-        let pos_chars: Vec<char> = positions.chars().collect();
+        for (index, c) in positions.chars().enumerate() {
+            self.set_rotor_position(index + 1, c)?;
+        }
+        self.pressed_since_set = false;
+        Ok(())
+    }
 
-        rotor1.set_position(pos_chars[0])?;
-        rotor2.set_position(pos_chars[1])?;
-        rotor3.set_position(pos_chars[2])?;
+    /// Sets a single rotor's position, leaving the others where they are.
+    /// `index` counts from 1 like the rotor names, so 1 is the fast rotor.
+    fn set_rotor_position(&mut self, index: usize, c: char) -> Result<(), EnigmaError> {
+        match index {
+            1 => self.rotor1.set_position(c),
+            2 => self.rotor2.set_position(c),
+            3 => self.rotor3.set_position(c),
+            _ => Err(EnigmaError::InvalidMessage(format!(
+                "Rotor index must be 1, 2 or 3, not {}",
+                index
+            ))),
+        }
+    }
 
-        let plugboard = if let Some(pb_file) = plugboard_file {
-            if Path::new(pb_file).exists() {
-                Self::load_plugboard(pb_file)?
-            } else {
-                Plugboard::new()
+    fn load_rotor_state(file_path: &str) -> Result<RotorState, EnigmaError> {
+        if !Path::new(file_path).exists() {
+            return Err(EnigmaError::FileError(format!(
+                "Rotor file '{}' not found",
+                file_path
+            )));
+        }
+
+        let rotor_state = if file_path.ends_with(".toml") {
+            let contents = std::fs::read_to_string(file_path)?;
+            let text_state: RotorState = toml::from_str(&contents)?;
+            RotorState {
+                rotor1: parse_wiring(&text_state.rotor1)?,
+                rotor2: parse_wiring(&text_state.rotor2)?,
+                rotor3: parse_wiring(&text_state.rotor3)?,
+                reflector: text_state
+                    .reflector
+                    .as_deref()
+                    .map(parse_wiring)
+                    .transpose()?,
             }
         } else {
-            Plugboard::new()
+            // Files without a reflector hold just the three wirings, as
+            // written before the reflector could be stored.
+            let contents = std::fs::read(file_path)?;
+            match bincode::deserialize(&contents) {
+                Ok(rotor_state) => rotor_state,
+                Err(_) => {
+                    let (rotor1, rotor2, rotor3) = bincode::deserialize(&contents)?;
+                    RotorState {
+                        rotor1,
+                        rotor2,
+                        rotor3,
+                        reflector: None,
+                    }
+                }
+            }
         };
 
-        Ok(Self {
-            rotor1,
-            rotor2,
-            rotor3,
-            reflector: Reflector::new(),
-            plugboard,
-        })
+        rotor_state.validate()?;
+        Ok(rotor_state)
+    }
+
+    /// Reads the `notches` key of a `.toml` rotor file. Bincode rotor files
+    /// and `.toml` files without the key have no notches of their own.
+    fn load_rotor_notches(file_path: &str) -> Result<Option<[usize; 3]>, EnigmaError> {
+        if !file_path.ends_with(".toml") {
+            return Ok(None);
+        }
+
+        let contents = std::fs::read_to_string(file_path)?;
+        let rotor_notches: RotorNotches = toml::from_str(&contents)?;
+        rotor_notches
+            .notches
+            .as_deref()
+            .map(parse_notches)
+            .transpose()
+    }
+
+    /// Loads and merges every plugboard file that exists; missing files
+    /// contribute no plugs.
+    fn load_optional_plugboard(plugboard_files: &[&str]) -> Result<Plugboard, EnigmaError> {
+        let mut sources = Vec::new();
+        for &pb_file in plugboard_files {
+            if Path::new(pb_file).exists() {
+                sources.push((pb_file, Self::load_plugboard(pb_file)?));
+            }
+        }
+
+        Plugboard::merge(&sources)
     }
 
     fn load_plugboard(file_path: &str) -> Result<Plugboard, EnigmaError> {
@@ -278,222 +767,4170 @@ impl EnigmaMachine {
         Plugboard::from_pairs(config.pairs)
     }
 
-    fn step_rotors(&mut self) {
-        let right_stepping = true;
-        let middle_at_notch = self.rotor2.at_notch();
-        let right_at_notch = self.rotor1.at_notch();
-
-        if middle_at_notch {
-            self.rotor2.step();
-            self.rotor3.step();
-        }
-
-        if right_at_notch {
-            self.rotor2.step();
-        }
+    fn load_reflector(file_path: &str) -> Result<Reflector, EnigmaError> {
+        let contents = std::fs::read_to_string(file_path)?;
+        let config: PlugboardConfig = toml::from_str(&contents)?;
 
-        if right_stepping {
-            self.rotor1.step();
-        }
+        Reflector::from_pairs(config.pairs)
     }
 
-    fn encode_char(&mut self, c: char) -> Result<char, EnigmaError> {
-        if !ALPHABET.contains(c) {
-            return Err(EnigmaError::InvalidMessage(format!(
-                "Invalid character: {}",
-                c
-            )));
-        }
+    fn positions(&self) -> String {
+        [&self.rotor1, &self.rotor2, &self.rotor3]
+            .iter()
+            .map(|rotor| rotor.position_char())
+            .collect()
+    }
 
-        self.step_rotors();
+    fn set_notches(&mut self, notches: [usize; 3]) -> Result<(), EnigmaError> {
+        self.rotor1.set_notch(notches[0])?;
+        self.rotor2.set_notch(notches[1])?;
+        self.rotor3.set_notch(notches[2])?;
+        Ok(())
+    }
 
-        let plugboard_out = self.plugboard.swap(c);
-        let mut signal = ALPHABET.find(plugboard_out).unwrap();
+    fn explain(&self) -> String {
+        let notch_letters: Vec<String> = [&self.rotor1, &self.rotor2, &self.rotor3]
+            .iter()
+            .map(|rotor| format!("'{}'", ALPHABET.chars().nth(rotor.notch_position).unwrap()))
+            .collect();
+        let positions: Vec<String> = self
+            .positions()
+            .chars()
+            .map(|c| format!("'{}'", c))
+            .collect();
 
-        signal = self.rotor1.encode_forward(signal);
-        signal = self.rotor2.encode_forward(signal);
-        signal = self.rotor3.encode_forward(signal);
+        let mut text = format!(
+            "This machine has 3 rotors. The signal enters the right (fast) rotor, \
+             then the middle rotor, then the left (slow) rotor. Their start positions \
+             are {} from right to left, and their notch letters are {}. The fast rotor \
+             steps on every keypress and turns the middle rotor over when it leaves its \
+             notch; the middle rotor double-steps the left rotor in the same way. ",
+            positions.join(", "),
+            notch_letters.join(", ")
+        );
 
-        signal = self.reflector.reflect(signal);
+        let reflector_fixed = (0..ALPHABET.len())
+            .find(|&i| self.reflector.reflect(i) == i)
+            .unwrap();
+        text.push_str(&format!(
+            "The reflector pairs up the alphabet characters, is set to '{}', \
+             and reflects '{}' onto itself. ",
+            ALPHABET.chars().nth(self.reflector.position).unwrap(),
+            ALPHABET.chars().nth(reflector_fixed).unwrap()
+        ));
 
-        signal = self.rotor3.encode_backward(signal);
-        signal = self.rotor2.encode_backward(signal);
-        signal = self.rotor1.encode_backward(signal);
+        let pairs = self.plugboard.pairs();
+        if pairs.is_empty() {
+            text.push_str("No plugboard pairs are connected.");
+        } else {
+            let pairs: Vec<String> = pairs
+                .iter()
+                .map(|(a, b)| format!("'{}' <-> '{}'", a, b))
+                .collect();
+            text.push_str(&format!(
+                "The plugboard swaps {} pair(s): {}.",
+                pairs.len(),
+                pairs.join(", ")
+            ));
+        }
 
-        let output_char = ALPHABET.chars().nth(signal).unwrap();
-        let final_char = self.plugboard.swap(output_char);
+        if let Some((_, switch_position)) = &self.switched_plugboard {
+            text.push_str(&format!(
+                " A second plugboard replaces it while the fast rotor is at or past '{}'.",
+                ALPHABET.chars().nth(*switch_position).unwrap()
+            ));
+        }
 
-        Ok(final_char)
+        text
     }
 
-    fn encode_message(&mut self, message: &str) -> Result<String, EnigmaError> {
-        if message.is_empty() {
-            return Err(EnigmaError::InvalidMessage("Empty message".to_string()));
-        }
-
-        let mut result = String::with_capacity(message.len());
+    fn inspect(&self) -> String {
+        let mut text = String::new();
 
-        for c in message.chars() {
-            result.push(self.encode_char(c)?);
+        for (i, rotor) in [&self.rotor1, &self.rotor2, &self.rotor3]
+            .iter()
+            .enumerate()
+        {
+            let notches: Vec<String> = rotor
+                .notch_positions_in_cycle()
+                .iter()
+                .map(|&notch| format!("'{}' ({})", ALPHABET.chars().nth(notch).unwrap(), notch))
+                .collect();
+            text.push_str(&format!(
+                "rotor{}: wiring \"{}\", position '{}', notch {}\n",
+                i + 1,
+                rotor.wiring,
+                rotor.position_char(),
+                notches.join(", ")
+            ));
         }
+        text.push_str(&format!(
+            "middle rotor steps in {} keypress(es)\n",
+            self.presses_to_next_turnover()
+        ));
 
-        Ok(result)
+        text
     }
-}
 
-fn generate_rotors(output_file: &str) -> Result<(), EnigmaError> {
-    let mut rng = thread_rng();
-    let mut rotors = Vec::with_capacity(3);
-
-    for _ in 0..3 {
-        let mut chars: Vec<char> = ALPHABET.chars().collect();
+    /// Lists weaknesses in the current configuration, each with a suggested
+    /// fix, followed by informational notes about properties no configuration
+    /// can avoid. An empty warning list means no weakness was found.
+    fn lint(&self) -> (Vec<String>, Vec<String>) {
+        let mut warnings = Vec::new();
+        let mut notes = Vec::new();
+        let rotors = [&self.rotor1, &self.rotor2, &self.rotor3];
 
-        loop {
-            chars.shuffle(&mut rng);
-            let rotor: String = chars.iter().collect();
+        if !self.plugboard.is_active() {
+            warnings.push(
+                "Plugboard is empty; add pairs to the plugboard file to enlarge the keyspace"
+                    .to_string(),
+            );
+        }
 
-            let has_fixed_point = ALPHABET
+        for (i, rotor) in rotors.iter().enumerate() {
+            let fixed: String = ALPHABET
                 .chars()
-                .enumerate()
-                .any(|(i, c)| rotor.chars().nth(i).unwrap() == c);
-
-            if !has_fixed_point {
+                .zip(rotor.wiring.chars())
+                .filter(|(a, b)| a == b)
+                .map(|(a, _)| a)
+                .collect();
+            if !fixed.is_empty() {
+                warnings.push(format!(
+                    "Rotor {} maps '{}' to itself; regenerate the rotor file with --generate",
+                    i + 1,
+                    fixed
+                ));
+            }
+        }
+
+        for i in 0..rotors.len() {
+            for j in (i + 1)..rotors.len() {
+                if rotors[i].wiring == rotors[j].wiring {
+                    warnings.push(format!(
+                        "Rotors {} and {} share the same wiring; regenerate the rotor file with --generate",
+                        i + 1,
+                        j + 1
+                    ));
+                }
+            }
+        }
+
+        if self.positions() == "aaa" {
+            warnings.push(
+                "Start positions are the default \"aaa\"; choose positions with --start-positions"
+                    .to_string(),
+            );
+        }
+
+        let fixed: String = ALPHABET
+            .chars()
+            .enumerate()
+            .filter(|&(i, _)| self.reflector.reflect(i) == i)
+            .map(|(_, a)| a)
+            .collect();
+        if fixed.chars().count() > ALPHABET.len() % 2 {
+            warnings.push(format!(
+                "Reflector maps '{}' to itself; load a reflector with all but {} character(s) paired",
+                fixed,
+                ALPHABET.len() % 2
+            ));
+        } else if !fixed.is_empty() {
+            notes.push(format!(
+                "Reflector maps '{}' to itself; the odd-length alphabet makes this unavoidable, \
+                 so keep messages short to limit self-encryptions",
+                fixed
+            ));
+        }
+
+        (warnings, notes)
+    }
+
+    fn step_rotors(&mut self) -> StepInfo {
+        let right_stepping = true;
+        let middle_at_notch = self.rotor2.at_notch();
+        let right_at_notch = self.rotor1.at_notch();
+
+        // The middle rotor's pawl engages once per keypress, even when both
+        // the fast rotor and the middle rotor sit on their notches.
+        if middle_at_notch || right_at_notch {
+            self.rotor2.step();
+        }
+
+        if middle_at_notch {
+            self.rotor3.step();
+        }
+
+        if right_stepping {
+            self.rotor1.step();
+        }
+
+        StepInfo {
+            rotor1: right_stepping,
+            rotor2: middle_at_notch || right_at_notch,
+            rotor3: middle_at_notch,
+        }
+    }
+
+    /// Returns how many keypresses it takes for the rotor positions to repeat.
+    /// The double step makes some positions unreachable once left (a middle
+    /// rotor resting on its notch, for example), so if the current positions
+    /// are such a transient state the machine never returns to them; the
+    /// period is then that of the cycle it settles into.
+    fn stepping_period(&self) -> usize {
+        let mut machine = self.clone();
+        let mut first_seen = HashMap::new();
+
+        for presses in 0.. {
+            let state = (
+                machine.rotor1.position,
+                machine.rotor2.position,
+                machine.rotor3.position,
+            );
+            if let Some(previous) = first_seen.insert(state, presses) {
+                return presses - previous;
+            }
+            machine.step_rotors();
+        }
+
+        unreachable!()
+    }
+
+    /// Drives a copy of the machine through one full stepping period and
+    /// checks that it lands back on the start positions. With one notch per
+    /// rotor the period must be `n * (n - 1) * n` for an alphabet of `n`
+    /// characters, since the double step skips one middle rotor position.
+    fn verify_stepping(&self) -> Result<usize, EnigmaError> {
+        let period = self.stepping_period();
+        let len = ALPHABET.len();
+        let expected = len * (len - 1) * len;
+        if period != expected {
+            return Err(EnigmaError::InvalidMessage(format!(
+                "stepping period is {}, expected {}",
+                period, expected
+            )));
+        }
+
+        let mut machine = self.clone();
+        machine.advance(period);
+        if machine.positions() != self.positions() {
+            return Err(EnigmaError::InvalidMessage(format!(
+                "rotors are at \"{}\" after {} presses, expected \"{}\"; \
+                 the start positions are a state the double step never revisits",
+                machine.positions(),
+                period,
+                self.positions()
+            )));
+        }
+
+        Ok(period)
+    }
+
+    /// Steps the rotors for a keypress, unless `skip_first_step` applies.
+    fn press_key(&mut self) -> StepInfo {
+        let skip = self.skip_first_step && !self.pressed_since_set;
+        self.pressed_since_set = true;
+
+        if skip {
+            StepInfo {
+                rotor1: false,
+                rotor2: false,
+                rotor3: false,
+            }
+        } else {
+            self.step_rotors()
+        }
+    }
+
+    /// Returns how many keypresses it takes until the middle rotor next
+    /// steps, counting the press that steps it. A middle rotor resting on its
+    /// notch steps on the very next press (the double step), so the answer is
+    /// 1 there as well as when the fast rotor sits on its notch.
+    fn presses_to_next_turnover(&self) -> usize {
+        let mut machine = self.clone();
+        let mut presses = 1;
+        while !machine.press_key().rotor2 {
+            presses += 1;
+        }
+        presses
+    }
+
+    fn advance(&mut self, n: usize) {
+        for _ in 0..n {
+            self.step_rotors();
+        }
+    }
+
+    fn snapshot(&self) -> MachineState {
+        MachineState {
+            positions: self.positions(),
+            notches: [&self.rotor1, &self.rotor2, &self.rotor3]
+                .iter()
+                .map(|rotor| ALPHABET.chars().nth(rotor.notch_position).unwrap())
+                .collect(),
+            reflector_position: ALPHABET.chars().nth(self.reflector.position).unwrap(),
+            pairs: self.plugboard.to_config().pairs,
+        }
+    }
+
+    /// Applies a snapshot taken by `snapshot`. Nothing is changed unless the
+    /// whole snapshot is valid.
+    fn restore(&mut self, state: &MachineState) -> Result<(), EnigmaError> {
+        let mut machine = self.clone();
+        machine.set_positions(&state.positions)?;
+        machine.set_notches(parse_notches(&state.notches)?)?;
+        machine.reflector.set_position(state.reflector_position)?;
+        machine.plugboard = Plugboard::from_pairs(state.pairs.clone())?;
+
+        *self = machine;
+        Ok(())
+    }
+
+    /// Installs a second plugboard that replaces the primary one whenever the
+    /// fast rotor is at or past `switch_position`. This stepping plugboard is
+    /// an experiment and has no historical counterpart.
+    fn set_switched_plugboard(
+        &mut self,
+        plugboard: Plugboard,
+        switch_position: char,
+    ) -> Result<(), EnigmaError> {
+        let switch_position = ALPHABET
+            .find(switch_position)
+            .ok_or(EnigmaError::InvalidRotorPosition(switch_position))?;
+        self.switched_plugboard = Some((plugboard, switch_position));
+        Ok(())
+    }
+
+    fn active_plugboard(&self) -> &Plugboard {
+        match &self.switched_plugboard {
+            Some((plugboard, switch_position)) if self.rotor1.position >= *switch_position => {
+                plugboard
+            }
+            _ => &self.plugboard,
+        }
+    }
+
+    fn encode_char(&mut self, c: char) -> Result<char, EnigmaError> {
+        self.encode_char_detailed(c).map(|(encoded, _)| encoded)
+    }
+
+    /// Like `encode_char`, but also reports which rotors stepped.
+    fn encode_char_detailed(&mut self, c: char) -> Result<(char, StepInfo), EnigmaError> {
+        if !ALPHABET.contains(c) {
+            return Err(EnigmaError::InvalidMessage(format!(
+                "Invalid character: {}",
+                c
+            )));
+        }
+
+        let steps = self.press_key();
+
+        Ok((self.substitute(c), steps))
+    }
+
+    /// Passes `c` through the signal path at the current positions without
+    /// stepping the rotors. `c` must be in the alphabet.
+    fn substitute(&self, c: char) -> char {
+        let plugboard = self.active_plugboard();
+        let plugboard_out = match self.plugboard_mode {
+            PlugboardMode::ExitOnly => c,
+            _ => plugboard.swap(c),
+        };
+        let mut signal = ALPHABET.find(plugboard_out).unwrap();
+
+        signal = self.entry_wheel.forward(signal);
+        signal = self.rotor1.encode_forward(signal);
+        signal = self.rotor2.encode_forward(signal);
+        signal = self.rotor3.encode_forward(signal);
+
+        signal = self.reflector.reflect(signal);
+
+        signal = self.rotor3.encode_backward(signal);
+        signal = self.rotor2.encode_backward(signal);
+        signal = self.rotor1.encode_backward(signal);
+        signal = self.entry_wheel.backward(signal);
+
+        let output_char = ALPHABET.chars().nth(signal).unwrap();
+        match self.plugboard_mode {
+            PlugboardMode::EntryOnly => output_char,
+            _ => plugboard.swap(output_char),
+        }
+    }
+
+    /// Searches for plugboards under which this machine's rotors, from the
+    /// current positions, encrypt `crib` to the start of `ciphertext`. Each
+    /// result lists the plugged pairs among the crib's characters; characters
+    /// outside the crib are not constrained. At most `MAX_PLUG_SOLUTIONS`
+    /// results are returned.
+    fn solve_plugs(
+        &self,
+        crib: &str,
+        ciphertext: &str,
+    ) -> Result<Vec<Vec<(char, char)>>, EnigmaError> {
+        if crib.chars().count() > ciphertext.chars().count() {
+            return Err(EnigmaError::InvalidMessage(
+                "Crib is longer than the ciphertext".to_string(),
+            ));
+        }
+
+        let mut scrambler = self.clone();
+        scrambler.plugboard = Plugboard::new();
+        scrambler.switched_plugboard = None;
+
+        let mut constraints = Vec::new();
+        for (p, c) in crib.chars().zip(ciphertext.chars()) {
+            let plain = ALPHABET
+                .find(p)
+                .ok_or_else(|| EnigmaError::InvalidMessage(format!("Invalid character: {}", p)))?;
+            let cipher = ALPHABET
+                .find(c)
+                .ok_or_else(|| EnigmaError::InvalidMessage(format!("Invalid character: {}", c)))?;
+            scrambler.press_key();
+            constraints.push(PlugConstraint {
+                plain,
+                cipher,
+                scrambler: ALPHABET
+                    .chars()
+                    .map(|c| ALPHABET.find(scrambler.substitute(c)).unwrap())
+                    .collect(),
+            });
+        }
+
+        let mut solutions = Vec::new();
+        search_plugs(vec![None; ALPHABET.len()], &constraints, &mut solutions);
+
+        let alphabet: Vec<char> = ALPHABET.chars().collect();
+        Ok(solutions
+            .iter()
+            .map(|partners| {
+                partners
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(a, &b)| b.filter(|&b| a < b).map(|b| (alphabet[a], alphabet[b])))
+                    .collect()
+            })
+            .collect())
+    }
+
+    /// Describes the configuration a run used as a JSON object, without any
+    /// message text. Wirings are given as FNV-1a fingerprints, which are
+    /// enough to tell whether two runs used the same rotors.
+    fn manifest(&self, start_positions: &str, modes: &[&str]) -> String {
+        let rotors = [&self.rotor1, &self.rotor2, &self.rotor3];
+        let wirings: String = rotors.iter().map(|rotor| rotor.wiring.as_str()).collect();
+        let notches: String = rotors
+            .iter()
+            .map(|rotor| ALPHABET.chars().nth(rotor.notch_position).unwrap())
+            .collect();
+        let pairs: Vec<String> = self
+            .plugboard
+            .to_config()
+            .pairs
+            .iter()
+            .map(|pair| json_string(pair))
+            .collect();
+        let modes: Vec<String> = modes.iter().map(|mode| json_string(mode)).collect();
+
+        format!(
+            "{{\"rotor_fingerprint\":\"{:016x}\",\"start_positions\":{},\"notches\":{},\
+             \"plug_pairs\":[{}],\"reflector_fingerprint\":\"{:016x}\",\
+             \"reflector_position\":{},\"entry_wheel_fingerprint\":\"{:016x}\",\"modes\":[{}]}}",
+            fnv1a(wirings.as_bytes()),
+            json_string(start_positions),
+            json_string(&notches),
+            pairs.join(","),
+            fnv1a(self.reflector.wiring.as_bytes()),
+            json_string(
+                &ALPHABET
+                    .chars()
+                    .nth(self.reflector.position)
+                    .unwrap()
+                    .to_string()
+            ),
+            fnv1a(self.entry_wheel.wiring.as_bytes()),
+            modes.join(",")
+        )
+    }
+
+    /// Returns the substitution applied at the current positions, as the
+    /// image of each alphabet character in order.
+    fn permutation(&self) -> String {
+        ALPHABET.chars().map(|c| self.substitute(c)).collect()
+    }
+
+    /// Passes `c` once through the rotors without the reflector: forward
+    /// when encrypting, backward when decrypting. The two directions are
+    /// inverses of each other, but neither is its own inverse, so unlike the
+    /// reflected path this cipher is not reciprocal.
+    fn substitute_one_way(&self, c: char, decrypt: bool) -> char {
+        let plugboard = self.active_plugboard();
+        let mut signal = ALPHABET.find(plugboard.swap(c)).unwrap();
+
+        if decrypt {
+            signal = self.rotor3.encode_backward(signal);
+            signal = self.rotor2.encode_backward(signal);
+            signal = self.rotor1.encode_backward(signal);
+            signal = self.entry_wheel.backward(signal);
+        } else {
+            signal = self.entry_wheel.forward(signal);
+            signal = self.rotor1.encode_forward(signal);
+            signal = self.rotor2.encode_forward(signal);
+            signal = self.rotor3.encode_forward(signal);
+        }
+
+        plugboard.swap(ALPHABET.chars().nth(signal).unwrap())
+    }
+
+    /// Encodes `message` with `substitute_one_way`, stepping the rotors as
+    /// usual. Decrypting needs `decrypt` set and the same start positions.
+    fn encode_message_one_way(
+        &mut self,
+        message: &str,
+        decrypt: bool,
+    ) -> Result<String, EnigmaError> {
+        if message.is_empty() {
+            return Err(EnigmaError::InvalidMessage("Empty message".to_string()));
+        }
+
+        let mut result = String::with_capacity(message.len());
+
+        for c in message.chars() {
+            if !ALPHABET.contains(c) {
+                return Err(EnigmaError::InvalidMessage(format!(
+                    "Invalid character: {}",
+                    c
+                )));
+            }
+            self.press_key();
+            result.push(self.substitute_one_way(c, decrypt));
+        }
+
+        Ok(result)
+    }
+
+    /// Reports whether both machines produce the same substitution at every
+    /// keypress over `EQUIVALENCE_SAMPLE_PRESSES` presses from their current
+    /// positions. This is a heuristic: the sample visits every fast and middle
+    /// rotor position but only a few left rotor positions, so machines that
+    /// diverge later in the stepping cycle are reported as equivalent.
+    fn is_equivalent_to(&self, other: &EnigmaMachine) -> bool {
+        let mut this = self.clone();
+        let mut other = other.clone();
+
+        (0..EQUIVALENCE_SAMPLE_PRESSES).all(|_| {
+            this.press_key();
+            other.press_key();
+            ALPHABET
+                .chars()
+                .all(|c| this.substitute(c) == other.substitute(c))
+        })
+    }
+
+    /// Encrypts `message_key` at the current (ground) positions to form the
+    /// indicator, then encrypts `message` starting from `message_key`. The
+    /// result is the three indicator characters followed by the ciphertext.
+    fn encode_with_indicator(
+        &mut self,
+        message_key: &str,
+        message: &str,
+    ) -> Result<String, EnigmaError> {
+        let indicator = self.encode_message(message_key)?;
+        self.set_positions(message_key)?;
+
+        Ok(indicator + &self.encode_message(message)?)
+    }
+
+    /// Reverses `encode_with_indicator`: decrypts the leading indicator at the
+    /// current (ground) positions to recover the message key, then decrypts
+    /// the remainder from that key.
+    fn decode_with_indicator(&mut self, ciphertext: &str) -> Result<String, EnigmaError> {
+        let indicator: String = ciphertext.chars().take(3).collect();
+        if indicator.chars().count() != 3 {
+            return Err(EnigmaError::InvalidMessage(
+                "Message is too short to contain an indicator".to_string(),
+            ));
+        }
+
+        let message_key = self.encode_message(&indicator)?;
+        self.set_positions(&message_key)?;
+
+        let body: String = ciphertext.chars().skip(3).collect();
+        self.encode_message(&body)
+    }
+
+    /// Encodes `input` line by line into `output` with continuous stepping
+    /// across lines. Line endings, `\n` or `\r\n`, are copied through without
+    /// pressing a key. When
+    /// `line_buffered` is set, output is flushed after every line; otherwise
+    /// only once at the end.
+    fn encode_stream<R: BufRead, W: Write>(
+        &mut self,
+        mut input: R,
+        output: W,
+        line_buffered: bool,
+    ) -> Result<(), EnigmaError> {
+        let mut writer = BufWriter::new(output);
+        let mut line = String::new();
+        let mut chars = Vec::new();
+
+        while input.read_line(&mut line)? > 0 {
+            let text = line
+                .strip_suffix("\r\n")
+                .or_else(|| line.strip_suffix('\n'))
+                .unwrap_or(&line);
+            chars.clear();
+            chars.extend(text.chars());
+            self.encode_slice_in_place(&mut chars)?;
+
+            for c in &chars {
+                write!(writer, "{}", c)?;
+            }
+            write!(writer, "{}", &line[text.len()..])?;
+            if line_buffered {
+                writer.flush()?;
+            }
+            line.clear();
+        }
+
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Encodes multi-line text, copying newlines through without a keypress.
+    /// Other characters outside the alphabet are an error unless
+    /// `passthrough` is set, in which case they are copied through as well.
+    fn encode_text(&mut self, text: &str, passthrough: bool) -> Result<String, EnigmaError> {
+        let mut result = String::with_capacity(text.len());
+
+        for c in text.chars() {
+            if c == '\n' || (passthrough && !ALPHABET.contains(c)) {
+                result.push(c);
+            } else {
+                result.push(self.encode_char(c)?);
+            }
+        }
+
+        Ok(result)
+    }
+
+    fn encode_message(&mut self, message: &str) -> Result<String, EnigmaError> {
+        self.encode_message_observed(message, |_| {})
+    }
+
+    /// Encodes `buf` in place, stepping the rotors for each character. On an
+    /// invalid character the error is returned with the characters before it
+    /// already encoded and the rest of `buf` untouched.
+    fn encode_slice_in_place(&mut self, buf: &mut [char]) -> Result<(), EnigmaError> {
+        for c in buf.iter_mut() {
+            *c = self.encode_char(*c)?;
+        }
+        Ok(())
+    }
+
+    /// Encodes `message`, calling `observer` after each keypress with the
+    /// machine in the state that was used to encode that character.
+    fn encode_message_observed<F>(
+        &mut self,
+        message: &str,
+        mut observer: F,
+    ) -> Result<String, EnigmaError>
+    where
+        F: FnMut(&Self),
+    {
+        if message.is_empty() {
+            return Err(EnigmaError::InvalidMessage("Empty message".to_string()));
+        }
+
+        let mut result = String::with_capacity(message.len());
+
+        for c in message.chars() {
+            result.push(self.encode_char(c)?);
+            observer(self);
+        }
+
+        Ok(result)
+    }
+}
+
+/// Writes `output` and a trailing newline to `output_file`. The file is only
+/// ever written sequentially and flushed before returning, so named pipes and
+/// other non-seekable targets work as well as regular files.
+fn write_output(output_file: &str, output: &str) -> Result<(), EnigmaError> {
+    let file = File::create(output_file)?;
+    let mut writer = BufWriter::new(file);
+
+    writeln!(writer, "{}", output)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Scores how much `text` looks like English by averaging the English
+/// frequency of each character, ignoring case. Space counts as the most
+/// frequent character. English plaintext scores around 0.08, while output
+/// from a wrong key stays near 0.034, the average for uniformly random
+/// alphabet characters.
+fn english_score(text: &str) -> f64 {
+    const SPACE_FREQUENCY: f64 = 0.18;
+    const LETTER_FREQUENCIES: [f64; 26] = [
+        0.082, 0.015, 0.028, 0.043, 0.127, 0.022, 0.020, 0.061, 0.070, 0.002, 0.008, 0.040, 0.024,
+        0.067, 0.075, 0.019, 0.001, 0.060, 0.063, 0.091, 0.028, 0.010, 0.024, 0.002, 0.020, 0.001,
+    ];
+
+    let total: f64 = text
+        .chars()
+        .map(|c| match c.to_ascii_lowercase() {
+            ' ' => SPACE_FREQUENCY,
+            c @ 'a'..='z' => {
+                LETTER_FREQUENCIES[(c as u8 - b'a') as usize] * (1.0 - SPACE_FREQUENCY)
+            }
+            _ => 0.0,
+        })
+        .sum();
+
+    total / text.chars().count().max(1) as f64
+}
+
+/// Decodes `message` with every rotor file in `dir`, each built into a
+/// machine by `load_machine`, and returns each file's path, English score and
+/// decoded text, best score first. Files that cannot be loaded as rotor files
+/// are skipped.
+fn try_keys<F>(
+    dir: &str,
+    load_machine: F,
+    message: &str,
+) -> Result<Vec<(String, f64, String)>, EnigmaError>
+where
+    F: Fn(&str) -> Result<EnigmaMachine, EnigmaError>,
+{
+    let mut paths: Vec<_> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file())
+        .collect();
+    paths.sort();
+
+    let mut results = Vec::new();
+    for path in paths {
+        let path = path.to_string_lossy().into_owned();
+        let Ok(mut machine) = load_machine(&path) else {
+            continue;
+        };
+        let decoded = machine.encode_message(message)?;
+        results.push((path, english_score(&decoded), decoded));
+    }
+
+    results.sort_by(|a, b| b.1.total_cmp(&a.1));
+    Ok(results)
+}
+
+/// One crib position: with plugboard `p` and scrambler `scrambler` (the
+/// machine without its plugboard at that keypress), the plaintext and
+/// ciphertext indices satisfy `p[cipher] == scrambler[p[plain]]`.
+struct PlugConstraint {
+    plain: usize,
+    cipher: usize,
+    scrambler: Vec<usize>,
+}
+
+/// Records that `a` and `b` are plugged together (or that `a` is unplugged
+/// when `a == b`). Returns false if either is already plugged elsewhere.
+fn assign_plug(partners: &mut [Option<usize>], a: usize, b: usize) -> bool {
+    match (partners[a], partners[b]) {
+        (Some(x), _) if x != b => false,
+        (_, Some(y)) if y != a => false,
+        _ => {
+            partners[a] = Some(b);
+            partners[b] = Some(a);
+            true
+        }
+    }
+}
+
+/// Applies every constraint whose plaintext or ciphertext plug is known
+/// until nothing changes. Returns false on a contradiction.
+fn propagate_plugs(partners: &mut [Option<usize>], constraints: &[PlugConstraint]) -> bool {
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for constraint in constraints {
+            let implied = match (partners[constraint.plain], partners[constraint.cipher]) {
+                (Some(plain), None) => Some((constraint.cipher, constraint.scrambler[plain])),
+                (None, Some(cipher)) => Some((constraint.plain, constraint.scrambler[cipher])),
+                (Some(plain), Some(cipher)) if constraint.scrambler[plain] != cipher => {
+                    return false;
+                }
+                _ => None,
+            };
+            if let Some((a, b)) = implied {
+                if !assign_plug(partners, a, b) {
+                    return false;
+                }
+                changed = true;
+            }
+        }
+    }
+    true
+}
+
+/// Guesses a partner for the first crib character whose plug is still
+/// unknown, propagates, and recurses, collecting up to `MAX_PLUG_SOLUTIONS`
+/// complete assignments.
+fn search_plugs(
+    partners: Vec<Option<usize>>,
+    constraints: &[PlugConstraint],
+    solutions: &mut Vec<Vec<Option<usize>>>,
+) {
+    if solutions.len() >= MAX_PLUG_SOLUTIONS {
+        return;
+    }
+
+    let unknown = constraints
+        .iter()
+        .flat_map(|constraint| [constraint.plain, constraint.cipher])
+        .find(|&i| partners[i].is_none());
+    let Some(letter) = unknown else {
+        solutions.push(partners);
+        return;
+    };
+
+    for partner in 0..ALPHABET.len() {
+        let mut guess = partners.clone();
+        if assign_plug(&mut guess, letter, partner) && propagate_plugs(&mut guess, constraints) {
+            search_plugs(guess, constraints, solutions);
+        }
+    }
+}
+
+/// Quotes every field so that leading and trailing spaces survive.
+fn csv_field(text: &str) -> String {
+    format!("\"{}\"", text.replace('"', "\"\""))
+}
+
+/// Encrypts `plaintext` from `EXPORT_VECTOR_COUNT` random start positions
+/// and renders the results as CSV rows of positions, plaintext and
+/// ciphertext, under a header row.
+fn export_vectors_csv<R: Rng + ?Sized>(
+    machine: &EnigmaMachine,
+    rng: &mut R,
+    plaintext: &str,
+) -> Result<String, EnigmaError> {
+    let mut csv = String::from("positions,plaintext,ciphertext\n");
+
+    for _ in 0..EXPORT_VECTOR_COUNT {
+        let positions = random_positions(rng);
+        let ciphertext = machine
+            .at_positions(&positions)?
+            .encode_message(plaintext)?;
+        csv.push_str(&format!(
+            "{},{},{}\n",
+            csv_field(&positions),
+            csv_field(plaintext),
+            csv_field(&ciphertext)
+        ));
+    }
+
+    Ok(csv)
+}
+
+fn json_string(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Describes, without touching the file system, what a write to
+/// `output_file` would do. Without `overwrite`, an existing file is reported
+/// as refused, matching `check_overwrite`.
+fn report_planned_write(output_file: &str, overwrite: bool) {
+    if !Path::new(output_file).exists() {
+        println!("Would create: {}", output_file);
+    } else if overwrite {
+        eprintln!("warning: '{}' already exists", output_file);
+        println!("Would overwrite: {}", output_file);
+    } else {
+        eprintln!(
+            "warning: '{}' already exists; use --force to overwrite it",
+            output_file
+        );
+        println!("Would refuse to overwrite: {}", output_file);
+    }
+}
+
+/// Writes `contents` to `output_file`, or with `dry_run` only reports the
+/// write. These outputs are always replaced, so an existing file is reported
+/// as overwritten.
+fn write_unless_dry_run(
+    output_file: &str,
+    contents: impl AsRef<[u8]>,
+    dry_run: bool,
+) -> Result<(), EnigmaError> {
+    if dry_run {
+        report_planned_write(output_file, true);
+    } else {
+        write(output_file, contents)?;
+    }
+    Ok(())
+}
+
+/// Rejects an `output_dir` at or inside `input_dir`, which `encode_tree`
+/// would otherwise walk into while writing it. `output_dir` need not exist
+/// yet; its closest existing ancestor is resolved instead.
+fn check_tree_dirs(input_dir: &Path, output_dir: &Path) -> Result<(), EnigmaError> {
+    let input = std::fs::canonicalize(input_dir)?;
+    let output = std::path::absolute(output_dir)?;
+    let existing = output
+        .ancestors()
+        .find(|ancestor| ancestor.exists())
+        .unwrap_or(&output);
+    let resolved = std::fs::canonicalize(existing)?.join(output.strip_prefix(existing).unwrap());
+
+    if resolved.starts_with(&input) {
+        return Err(EnigmaError::FileError(format!(
+            "Output directory '{}' is inside input directory '{}'",
+            output_dir.display(),
+            input_dir.display()
+        )));
+    }
+    Ok(())
+}
+
+/// Encodes every file under `input_dir` into the same relative path under
+/// `output_dir`, starting each file from the positions `machine` is at.
+/// Returns the number of files written. With `dry_run`, every file is still
+/// encoded, but only reported instead of written.
+fn encode_tree(
+    machine: &EnigmaMachine,
+    input_dir: &Path,
+    output_dir: &Path,
+    passthrough: bool,
+    dry_run: bool,
+) -> Result<usize, EnigmaError> {
+    if !dry_run {
+        std::fs::create_dir_all(output_dir)?;
+    }
+
+    let mut entries: Vec<_> = std::fs::read_dir(input_dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<_, _>>()?;
+    entries.sort();
+
+    let mut count = 0;
+    for input_path in entries {
+        let output_path = output_dir.join(input_path.file_name().unwrap());
+
+        if input_path.is_dir() {
+            count += encode_tree(machine, &input_path, &output_path, passthrough, dry_run)?;
+        } else {
+            let contents = std::fs::read_to_string(&input_path)?;
+            let encoded = machine
+                .clone()
+                .encode_text(&contents, passthrough)
+                .map_err(|e| EnigmaError::FileError(format!("{}: {}", input_path.display(), e)))?;
+            write_unless_dry_run(&output_path.to_string_lossy(), encoded, dry_run)?;
+            count += 1;
+        }
+    }
+
+    Ok(count)
+}
+
+/// Spells out digits so they can be encrypted. Each digit becomes
+/// `SPELLING_ESCAPE` followed by its name, and a literal `SPELLING_ESCAPE`
+/// is doubled, so `unspell_numbers` can always undo it.
+fn spell_numbers(text: &str) -> String {
+    let mut spelled = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c.to_digit(10) {
+            Some(digit) => {
+                spelled.push(SPELLING_ESCAPE);
+                spelled.push_str(DIGIT_NAMES[digit as usize]);
+            }
+            None if c == SPELLING_ESCAPE => spelled.extend([c, c]),
+            None => spelled.push(c),
+        }
+    }
+    spelled
+}
+
+/// Reverses `spell_numbers`. No digit name is a prefix of another, so the
+/// name after each escape is unambiguous.
+fn unspell_numbers(text: &str) -> Result<String, EnigmaError> {
+    let mut unspelled = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(index) = rest.find(SPELLING_ESCAPE) {
+        unspelled.push_str(&rest[..index]);
+        let after = &rest[index + SPELLING_ESCAPE.len_utf8()..];
+
+        if let Some(after) = after.strip_prefix(SPELLING_ESCAPE) {
+            unspelled.push(SPELLING_ESCAPE);
+            rest = after;
+            continue;
+        }
+
+        let (digit, name) = DIGIT_NAMES
+            .iter()
+            .enumerate()
+            .find(|(_, name)| after.starts_with(*name))
+            .ok_or_else(|| {
+                EnigmaError::InvalidMessage(format!(
+                    "'{}' is not followed by a spelled digit",
+                    SPELLING_ESCAPE
+                ))
+            })?;
+        unspelled.push(char::from_digit(digit as u32, 10).unwrap());
+        rest = &after[name.len()..];
+    }
+
+    unspelled.push_str(rest);
+    Ok(unspelled)
+}
+
+/// Prepares plaintext before encoding: `letters` keeps only ASCII letters,
+/// `letters-spaces` also keeps spaces, and `uppercase` uppercases every
+/// letter without dropping anything.
+fn apply_filter(text: &str, filter: &str) -> String {
+    match filter {
+        "letters" => text.chars().filter(|c| c.is_ascii_alphabetic()).collect(),
+        "letters-spaces" => text
+            .chars()
+            .filter(|&c| c.is_ascii_alphabetic() || c == ' ')
+            .collect(),
+        "uppercase" => text.to_ascii_uppercase(),
+        _ => text.to_string(),
+    }
+}
+
+fn check_message_length(message: &str, max_length: usize) -> Result<(), EnigmaError> {
+    let length = message.chars().count();
+    if length > max_length {
+        return Err(EnigmaError::InvalidMessage(format!(
+            "Message is {} characters, limit is {}",
+            length, max_length
+        )));
+    }
+
+    Ok(())
+}
+
+fn parse_notches(notches: &str) -> Result<[usize; 3], EnigmaError> {
+    let chars: Vec<char> = notches.chars().collect();
+    if chars.len() != 3 {
+        return Err(EnigmaError::InvalidMessage(
+            "Notches must be 3 characters".to_string(),
+        ));
+    }
+
+    let mut positions = [0; 3];
+    for (position, &c) in positions.iter_mut().zip(&chars) {
+        *position = ALPHABET
+            .find(c)
+            .ok_or_else(|| EnigmaError::InvalidMessage(format!("Invalid notch letter: {}", c)))?;
+    }
+
+    Ok(positions)
+}
+
+/// Formats the `--status-line` summary of a run as `key=value` pairs. The
+/// field order is fixed so that logs can be parsed.
+fn status_line(
+    input: &str,
+    output: &str,
+    start_positions: &str,
+    machine: &EnigmaMachine,
+) -> String {
+    format!(
+        "status input_len={} output_len={} start=\"{}\" end=\"{}\" plugboard={}",
+        input.chars().count(),
+        output.chars().count(),
+        start_positions,
+        machine.positions(),
+        if machine.plugboard.is_active() {
+            "on"
+        } else {
+            "off"
+        }
+    )
+}
+
+fn to_morse_groups(text: &str) -> String {
+    let chars: Vec<char> = text
+        .chars()
+        .map(|c| if c == ' ' { MORSE_WORD_SEPARATOR } else { c })
+        .collect();
+
+    chars
+        .chunks(MORSE_GROUP_SIZE)
+        .map(|group| group.iter().collect::<String>())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn from_morse_groups(groups: &str) -> String {
+    groups
+        .split_whitespace()
+        .flat_map(|group| group.chars())
+        .map(|c| if c == MORSE_WORD_SEPARATOR { ' ' } else { c })
+        .collect()
+}
+
+fn parse_wiring(wiring: &str) -> Result<String, EnigmaError> {
+    if wiring.starts_with('(') {
+        parse_cycle_notation(wiring)
+    } else {
+        Ok(wiring.to_string())
+    }
+}
+
+fn parse_cycle_notation(cycles: &str) -> Result<String, EnigmaError> {
+    let mut wiring: Vec<char> = ALPHABET.chars().collect();
+    let mut seen = HashSet::new();
+    let mut chars = cycles.chars();
+
+    while let Some(open) = chars.next() {
+        if open != '(' {
+            return Err(EnigmaError::InvalidRotorWiring(format!(
+                "Expected '(' in cycle notation, found '{}'",
+                open
+            )));
+        }
+
+        let mut cycle = Vec::new();
+        loop {
+            match chars.next() {
+                Some(')') => break,
+                Some(c) if ALPHABET.contains(c) => {
+                    if !seen.insert(c) {
+                        return Err(EnigmaError::InvalidRotorWiring(format!(
+                            "'{}' appears more than once in cycle notation",
+                            c
+                        )));
+                    }
+                    cycle.push(c);
+                }
+                Some(c) => {
+                    return Err(EnigmaError::InvalidRotorWiring(format!(
+                        "'{}' is not in the alphabet",
+                        c
+                    )));
+                }
+                None => {
+                    return Err(EnigmaError::InvalidRotorWiring(
+                        "Unterminated cycle".to_string(),
+                    ));
+                }
+            }
+        }
+
+        for (i, &c) in cycle.iter().enumerate() {
+            let image = cycle[(i + 1) % cycle.len()];
+            wiring[ALPHABET.find(c).unwrap()] = image;
+        }
+    }
+
+    Ok(wiring.into_iter().collect())
+}
+
+/// FNV-1a keeps hashes stable across platforms and Rust releases, unlike
+/// the standard library's hasher.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for &byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+fn seed_from_file(file_path: &str) -> Result<u64, EnigmaError> {
+    Ok(fnv1a(&std::fs::read(file_path)?))
+}
+
+fn check_overwrite(output_file: &str, force: bool) -> Result<(), EnigmaError> {
+    if !force && Path::new(output_file).exists() {
+        return Err(EnigmaError::FileError(format!(
+            "'{}' already exists; use --force to overwrite it",
+            output_file
+        )));
+    }
+
+    Ok(())
+}
+
+fn generate_rotors_seeded(
+    output_file: &str,
+    seed: Option<u64>,
+    reflector_seed: Option<u64>,
+    min_displacement: usize,
+    force: bool,
+) -> Result<RotorState, EnigmaError> {
+    // Without an explicit seed, draw one so the result can be reproduced.
+    let sampled = seed.is_none();
+    let seed = seed.unwrap_or_else(|| thread_rng().r#gen());
+
+    let rotor_state = generate_rotors(
+        output_file,
+        &mut StdRng::seed_from_u64(seed),
+        reflector_seed,
+        min_displacement,
+        force,
+    )?;
+
+    if sampled {
+        eprintln!(
+            "Generated with seed {} (pass --seed {} to reproduce)",
+            seed, seed
+        );
+    }
+    Ok(rotor_state)
+}
+
+/// Generates and saves random rotors. With `reflector_seed`, a random
+/// reflector drawn from its own seed is stored alongside them, so it can be
+/// varied without changing the rotors.
+fn generate_rotors<R: Rng + ?Sized>(
+    output_file: &str,
+    rng: &mut R,
+    reflector_seed: Option<u64>,
+    min_displacement: usize,
+    force: bool,
+) -> Result<RotorState, EnigmaError> {
+    check_overwrite(output_file, force)?;
+    is_text_rotor_file(output_file)?;
+
+    let mut rotor_state = random_rotor_state(rng, min_displacement);
+    rotor_state.reflector =
+        reflector_seed.map(|seed| Reflector::random(&mut StdRng::seed_from_u64(seed)).wiring);
+    save_rotor_state(&rotor_state, DEFAULT_NOTCHES, output_file)?;
+
+    println!("Rotor configuration saved to: {}", output_file);
+    Ok(rotor_state)
+}
+
+/// The displacement of a wiring entry is the circular distance, in alphabet
+/// positions, between a character and the character it is wired to. A
+/// wiring's displacement is the smallest such distance over all entries, so
+/// a displacement of 1 means the wiring has no fixed points.
+fn wiring_displacement(wiring: &str) -> usize {
+    let len = ALPHABET.len();
+
+    wiring
+        .chars()
+        .enumerate()
+        .map(|(i, c)| {
+            let distance = (ALPHABET.find(c).unwrap() + len - i) % len;
+            distance.min(len - distance)
+        })
+        .min()
+        .unwrap_or(0)
+}
+
+fn random_positions<R: Rng + ?Sized>(rng: &mut R) -> String {
+    let alphabet: Vec<char> = ALPHABET.chars().collect();
+    (0..3).map(|_| *alphabet.choose(rng).unwrap()).collect()
+}
+
+fn random_rotor_state<R: Rng + ?Sized>(rng: &mut R, min_displacement: usize) -> RotorState {
+    let mut rotors = Vec::with_capacity(3);
+
+    for _ in 0..3 {
+        let mut chars: Vec<char> = ALPHABET.chars().collect();
+
+        loop {
+            chars.shuffle(rng);
+            let rotor: String = chars.iter().collect();
+
+            if wiring_displacement(&rotor) >= min_displacement {
                 rotors.push(rotor);
                 break;
             }
         }
     }
 
-    let rotor_state = RotorState {
-        rotor1: rotors[0].clone(),
-        rotor2: rotors[1].clone(),
-        rotor3: rotors[2].clone(),
-    };
+    RotorState {
+        rotor1: rotors[0].clone(),
+        rotor2: rotors[1].clone(),
+        rotor3: rotors[2].clone(),
+        reflector: None,
+    }
+}
+
+/// Reports whether `output_file` should be written as `.toml` text rather
+/// than bincode. Bincode files use `.enigma`, `.bin` or no extension; any other
+/// extension is rejected instead of silently getting bincode.
+fn is_text_rotor_file(output_file: &str) -> Result<bool, EnigmaError> {
+    match Path::new(output_file)
+        .extension()
+        .and_then(|ext| ext.to_str())
+    {
+        Some("toml") => Ok(true),
+        None | Some("enigma") | Some("bin") => Ok(false),
+        Some(ext) => Err(EnigmaError::FileError(format!(
+            "Unsupported rotor file extension '.{}' for '{}'; use .toml for text or .enigma for bincode",
+            ext, output_file
+        ))),
+    }
+}
+
+/// Writes `rotor_state` in the format implied by the file extension: the
+/// `.toml` text format, which also records `notches`, or bincode.
+fn save_rotor_state(
+    rotor_state: &RotorState,
+    notches: [usize; 3],
+    output_file: &str,
+) -> Result<(), EnigmaError> {
+    if is_text_rotor_file(output_file)? {
+        write(output_file, rotor_state_to_text(rotor_state, notches)?)?;
+    } else {
+        let file = File::create(output_file)?;
+        let writer = BufWriter::new(file);
+
+        // Without a reflector, keep the layout older versions can read.
+        match rotor_state.reflector {
+            Some(_) => bincode::serialize_into(writer, rotor_state)?,
+            None => bincode::serialize_into(
+                writer,
+                &(
+                    &rotor_state.rotor1,
+                    &rotor_state.rotor2,
+                    &rotor_state.rotor3,
+                ),
+            )?,
+        }
+    }
+
+    Ok(())
+}
+
+fn rotor_state_to_text(
+    rotor_state: &RotorState,
+    notches: [usize; 3],
+) -> Result<String, EnigmaError> {
+    let notch_letters: String = notches
+        .iter()
+        .map(|&notch| ALPHABET.chars().nth(notch).unwrap())
+        .collect();
+
+    Ok(format!(
+        "{}notches = \"{}\"\n",
+        toml::to_string(rotor_state)?,
+        notch_letters
+    ))
+}
+
+fn generate_plugboard(output_file: &str, force: bool) -> Result<(), EnigmaError> {
+    check_overwrite(output_file, force)?;
+
+    let plugboard_content = format!(
+        r#"{}
+pairs = [
+    # "ab",  # a <-> b
+    # "CD",  # C <-> D
+    # "X ",  # X <-> space
+]
+"#,
+        PLUGBOARD_HEADER
+    );
+
+    write(output_file, plugboard_content)?;
+    println!("Plugboard configuration generated at: {}", output_file);
+    Ok(())
+}
+
+/// Picks `count` disjoint pairs by shuffling the alphabet and pairing
+/// consecutive characters, so no character can appear in two pairs.
+fn random_pairs<R: Rng + ?Sized>(rng: &mut R, count: usize) -> Vec<String> {
+    let mut chars: Vec<char> = ALPHABET.chars().collect();
+    chars.shuffle(rng);
+
+    chars
+        .chunks_exact(2)
+        .take(count)
+        .map(|pair| pair.iter().collect())
+        .collect()
+}
+
+fn generate_random_plugboard(
+    output_file: &str,
+    seed: Option<u64>,
+    count: usize,
+    force: bool,
+) -> Result<(), EnigmaError> {
+    check_overwrite(output_file, force)?;
+
+    let sampled = seed.is_none();
+    let seed = seed.unwrap_or_else(|| thread_rng().r#gen());
+
+    let config = PlugboardConfig {
+        pairs: random_pairs(&mut StdRng::seed_from_u64(seed), count),
+    };
+    write(output_file, config.to_toml())?;
+
+    println!("Plugboard configuration generated at: {}", output_file);
+    if sampled {
+        eprintln!(
+            "Generated with seed {} (pass --seed {} to reproduce)",
+            seed, seed
+        );
+    }
+    Ok(())
+}
+
+/// Replaces the default SIGINT action, which kills the process with output
+/// still buffered, by one that only records the interrupt. Without
+/// `SA_RESTART` a blocked read returns early, and `InterruptibleInput` then
+/// reports end of input so the caller finishes and flushes as usual.
+#[cfg(unix)]
+fn install_interrupt_handler() {
+    extern "C" fn on_interrupt(_signal: libc::c_int) {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+    }
+
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(libc::SIGINT, &action, std::ptr::null_mut());
+    }
+}
+
+#[cfg(not(unix))]
+fn install_interrupt_handler() {}
+
+/// Wraps a reader so it reports end of input once SIGINT has arrived.
+struct InterruptibleInput<R> {
+    inner: R,
+}
+
+impl<R: std::io::Read> std::io::Read for InterruptibleInput<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            return Ok(0);
+        }
+        self.inner.read(buf)
+    }
+}
+
+impl<R: BufRead> BufRead for InterruptibleInput<R> {
+    // An interrupted read surfaces as `ErrorKind::Interrupted`, which
+    // `read_line` retries, landing back here to see the flag.
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            return Ok(&[]);
+        }
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.inner.consume(amount);
+    }
+}
+
+/// The command-line settings applied on top of a loaded machine. Every
+/// machine a run builds (the main one, a layered second machine, the
+/// `--equivalent-to` comparison and each `--try-keys` candidate) gets the
+/// same overrides.
+#[derive(Default)]
+struct MachineOverrides {
+    plugboard: Option<Plugboard>,
+    notches: Option<[usize; 3]>,
+    entry_wheel: Option<EntryWheel>,
+    switched_plugboard: Option<(Plugboard, char)>,
+    skip_first_step: bool,
+    plugboard_mode: Option<PlugboardMode>,
+    reflector: Option<Reflector>,
+    reflector_position: Option<char>,
+}
+
+impl MachineOverrides {
+    fn from_matches(matches: &clap::ArgMatches) -> Result<Self, EnigmaError> {
+        let single_char = |value: &String, what: &str| {
+            let mut chars = value.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => Ok(c),
+                _ => Err(EnigmaError::InvalidMessage(format!(
+                    "{} must be 1 character",
+                    what
+                ))),
+            }
+        };
+
+        let mut overrides = Self {
+            skip_first_step: matches.get_flag("no_initial_step"),
+            ..Self::default()
+        };
+
+        if let Some(plugs) = matches.get_one::<String>("plugs") {
+            overrides.plugboard = Some(Plugboard::from_pairs(split_plugs(plugs))?);
+        }
+
+        if let Some(notches) = matches.get_one::<String>("notches") {
+            overrides.notches = Some(parse_notches(notches)?);
+        }
+
+        if let Some(etw) = matches.get_one::<String>("etw") {
+            overrides.entry_wheel = Some(EntryWheel::from_wiring(etw)?);
+        }
+
+        if let Some(switch_file) = matches.get_one::<String>("switch_plugboard_file") {
+            let switch_position = matches.get_one::<String>("plugboard_switch").unwrap();
+            let switch_position = single_char(switch_position, "Plugboard switch position")?;
+            overrides.switched_plugboard =
+                Some((EnigmaMachine::load_plugboard(switch_file)?, switch_position));
+        }
+
+        if matches.get_one::<String>("plugboard_mode").unwrap() == "entry-only" {
+            overrides.plugboard_mode = Some(if matches.get_flag("decrypt") {
+                PlugboardMode::ExitOnly
+            } else {
+                PlugboardMode::EntryOnly
+            });
+        }
+
+        if let Some(reflector_file) = matches.get_one::<String>("reflector_file") {
+            overrides.reflector = Some(EnigmaMachine::load_reflector(reflector_file)?);
+        }
+
+        if let Some(fixed) = matches.get_one::<String>("reflector_fixed") {
+            let fixed = single_char(fixed, "Reflector fixed point")?;
+            overrides.reflector = Some(Reflector::with_fixed_point(fixed)?);
+        }
+
+        if let Some(reflector_position) = matches.get_one::<String>("reflector_position") {
+            overrides.reflector_position =
+                Some(single_char(reflector_position, "Reflector position")?);
+        }
+
+        Ok(overrides)
+    }
+
+    fn apply(&self, machine: &mut EnigmaMachine) -> Result<(), EnigmaError> {
+        if let Some(plugboard) = &self.plugboard {
+            machine.plugboard = plugboard.clone();
+        }
+        if let Some(notches) = self.notches {
+            machine.set_notches(notches)?;
+        }
+        if let Some(entry_wheel) = &self.entry_wheel {
+            machine.entry_wheel = entry_wheel.clone();
+        }
+        if let Some((plugboard, switch_position)) = &self.switched_plugboard {
+            machine.set_switched_plugboard(plugboard.clone(), *switch_position)?;
+        }
+        machine.skip_first_step = self.skip_first_step;
+        if let Some(plugboard_mode) = self.plugboard_mode {
+            machine.plugboard_mode = plugboard_mode;
+        }
+        if let Some(reflector) = &self.reflector {
+            machine.reflector = reflector.clone();
+        }
+        if let Some(reflector_position) = self.reflector_position {
+            machine.reflector.set_position(reflector_position)?;
+        }
+        Ok(())
+    }
+}
+
+/// Splits a `--plugs` value into pairs. Whitespace separates the pairs, so
+/// the space character cannot be plugged this way.
+fn split_plugs(plugs: &str) -> Vec<String> {
+    plugs.split_whitespace().map(String::from).collect()
+}
+
+fn main() {
+    let matches = Command::new("enigma")
+        .version("3.0")
+        .author("Definitely not the Nazis")
+        .about("A proper WWII Enigma machine cipher with rotors and plugboard.")
+        .arg(
+            Arg::new("generate")
+                .short('g')
+                .long("generate")
+                .help("Generate new rotor configuration")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("generate_plugboard")
+                .short('p')
+                .long("generate-plugboard")
+                .help("Generate plugboard configuration file")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("print_wirings")
+                .long("print-wirings")
+                .help("Print the generated wirings and notch letters as a text rotor file")
+                .action(clap::ArgAction::SetTrue)
+                .requires("generate"),
+        )
+        .arg(
+            Arg::new("min_displacement")
+                .long("min-displacement")
+                .value_name("N")
+                .help("Minimum circular distance between each character and its wiring (1-5)")
+                .value_parser(clap::value_parser!(u64).range(1..=MAX_MIN_DISPLACEMENT))
+                .default_value("1"),
+        )
+        .arg(
+            Arg::new("convert")
+                .long("convert")
+                .value_name("OUT_FILE")
+                .help("Convert the rotor file to OUT_FILE (.toml for text, otherwise bincode)")
+                .conflicts_with("generate"),
+        )
+        .arg(
+            Arg::new("force")
+                .short('f')
+                .long("force")
+                .help("Allow the generate commands to overwrite existing files")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("dry_run")
+                .long("dry-run")
+                .help("Report which files would be written without writing any of them")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("seed")
+                .long("seed")
+                .value_name("SEED")
+                .help("Seed for reproducible rotor generation, --random-plugs, --random-start and --export-vectors-csv")
+                .value_parser(clap::value_parser!(u64)),
+        )
+        .arg(
+            Arg::new("random_plugs")
+                .long("random-plugs")
+                .value_name("N")
+                .help("With --generate-plugboard, plug N random disjoint pairs (0-26)")
+                .value_parser(clap::value_parser!(u64).range(0..=26))
+                .requires("generate_plugboard"),
+        )
+        .arg(
+            Arg::new("reflector_seed")
+                .long("reflector-seed")
+                .value_name("SEED")
+                .help("With --generate, also store a random reflector drawn from SEED")
+                .value_parser(clap::value_parser!(u64))
+                .requires("generate"),
+        )
+        .arg(
+            Arg::new("seed_file")
+                .long("seed-file")
+                .value_name("PATH")
+                .help("Derive the generation seed from a shared file's contents")
+                .conflicts_with("seed"),
+        )
+        .arg(
+            Arg::new("rotor_file")
+                .short('r')
+                .long("rotor-file")
+                .value_name("FILE")
+                .help("Path to rotor configuration file")
+                .env("ENIGMA_ROTOR_FILE")
+                .default_value(DEFAULT_ROTOR_FILE),
+        )
+        .arg(
+            Arg::new("plugboard_file")
+                .short('b')
+                .long("plugboard-file")
+                .value_name("FILE")
+                .help("Path to plugboard configuration file (repeat to merge several)")
+                .action(clap::ArgAction::Append)
+                .env("ENIGMA_PLUGBOARD_FILE")
+                .default_value(DEFAULT_PLUGBOARD_FILE),
+        )
+        .arg(
+            Arg::new("plugs")
+                .long("plugs")
+                .value_name("PAIRS")
+                .help("Whitespace-separated plugboard pairs, replacing the plugboard file"),
+        )
+        .arg(
+            Arg::new("notches")
+                .short('n')
+                .long("notches")
+                .value_name("LETTERS")
+                .help("Override the notch letter of each rotor (3 chars)"),
+        )
+        .arg(
+            Arg::new("etw")
+                .long("etw")
+                .value_name("WIRING")
+                .help("Entry wheel permutation as a wiring string or cycle notation"),
+        )
+        .arg(
+            Arg::new("config")
+                .short('c')
+                .long("config")
+                .value_name("FILE")
+                .help("Combined TOML file with rotor wirings and plugboard pairs"),
+        )
+        .arg(
+            Arg::new("switch_plugboard_file")
+                .long("switch-plugboard-file")
+                .value_name("FILE")
+                .help("Plugboard used while the fast rotor is at or past --plugboard-switch")
+                .requires("plugboard_switch"),
+        )
+        .arg(
+            Arg::new("plugboard_switch")
+                .long("plugboard-switch")
+                .value_name("CHAR")
+                .help("Fast rotor position at which the switch plugboard takes over")
+                .requires("switch_plugboard_file"),
+        )
+        .arg(
+            Arg::new("positions")
+                .short('s')
+                .long("start-positions")
+                .value_name("POSITIONS")
+                .help("Initial rotor positions (3 chars)")
+                .default_value("aaa"),
+        )
+        .arg(
+            Arg::new("advance")
+                .long("advance")
+                .value_name("N")
+                .help("Step the rotors N times before encoding")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("0"),
+        )
+        .arg(
+            Arg::new("set_rotor")
+                .long("set-rotor")
+                .value_name("INDEX:CHAR")
+                .help("After --advance, set one rotor (1 = fast, 3 = slow) to CHAR; repeatable")
+                .action(clap::ArgAction::Append),
+        )
+        .arg(
+            Arg::new("second_rotor_file")
+                .long("second-rotor-file")
+                .value_name("FILE")
+                .help("Rotor file for a second machine layered after the first"),
+        )
+        .arg(
+            Arg::new("second_positions")
+                .long("second-positions")
+                .value_name("POSITIONS")
+                .help("Initial rotor positions for the second machine (3 chars)")
+                .default_value("aaa")
+                .requires("second_rotor_file"),
+        )
+        .arg(
+            Arg::new("decrypt")
+                .short('d')
+                .long("decrypt")
+                .help("Decrypt: reverse layered machines, read the indicator and invert one-way modes")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("status_line")
+                .long("status-line")
+                .help("Print a key=value run summary to stderr")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("morse_groups")
+                .long("morse-groups")
+                .help("Print output in 5-character groups with '/' for space")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("from_morse_groups")
+                .long("from-morse-groups")
+                .help("Read the message as 5-character groups with '/' for space")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("positions_log")
+                .long("positions-log")
+                .value_name("FILE")
+                .help("Write the rotor positions used for each character to FILE"),
+        )
+        .arg(
+            Arg::new("no_initial_step")
+                .long("no-initial-step")
+                .help("Do not step on the first keypress after setting the rotors, to match tools that step after encoding")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("plugboard_mode")
+                .long("plugboard-mode")
+                .value_name("MODE")
+                .help("Plug both the entry and exit of the signal, or only the entry (not reciprocal; decrypt with -d)")
+                .value_parser(["both", "entry-only"])
+                .default_value("both")
+                .conflicts_with_all(["no_reflector", "solve_plugs"]),
+        )
+        .arg(
+            Arg::new("reflector_file")
+                .long("reflector-file")
+                .value_name("FILE")
+                .help("Load the reflector wiring from a pairs file in the plugboard format"),
+        )
+        .arg(
+            Arg::new("reflector_fixed")
+                .long("reflector-fixed")
+                .value_name("CHAR")
+                .help("Choose the one character the reflector maps onto itself (default: space)")
+                .conflicts_with("reflector_file"),
+        )
+        .arg(
+            Arg::new("dump_permutations")
+                .long("dump-permutations")
+                .value_name("FILE")
+                .help("Write the full substitution used for each character to FILE, one per line")
+                .conflicts_with_all(["indicator", "ground_setting", "no_reflector"]),
+        )
+        .arg(
+            Arg::new("reflector_position")
+                .long("reflector-position")
+                .value_name("CHAR")
+                .help("Set the reflector to a fixed offset (does not step)"),
+        )
+        .arg(
+            Arg::new("space_as")
+                .long("space-as")
+                .value_name("CHAR")
+                .help("Show spaces as CHAR in the output and read CHAR back as a space"),
+        )
+        .arg(
+            Arg::new("preview")
+                .long("preview")
+                .value_name("N")
+                .help("Print only the first N characters of the output")
+                .value_parser(clap::value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("indicator")
+                .long("indicator")
+                .help("Prefix the ciphertext with the message key encrypted at the start positions")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with("second_rotor_file"),
+        )
+        .arg(
+            Arg::new("ground_setting")
+                .long("ground-setting")
+                .value_name("POSITIONS")
+                .help(
+                    "Start from the ground setting and send a random message key in the indicator",
+                )
+                .conflicts_with_all(["positions", "second_rotor_file"]),
+        )
+        .group(
+            ArgGroup::new("indicator_mode")
+                .args(["indicator", "ground_setting"])
+                .multiple(true),
+        )
+        .arg(
+            Arg::new("message_key")
+                .long("message-key")
+                .value_name("POSITIONS")
+                .help("Per-message rotor positions sent in the indicator (3 chars)")
+                .requires("indicator_mode"),
+        )
+        .arg(
+            Arg::new("equivalent_to")
+                .long("equivalent-to")
+                .value_name("FILE")
+                .help("Check whether another rotor file behaves identically at these settings"),
+        )
+        .arg(
+            Arg::new("explain")
+                .long("explain")
+                .help("Describe the effective configuration in prose on stderr")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("export_plugboard")
+                .long("export-plugboard")
+                .value_name("FILE")
+                .help("Write the loaded plugboard pairs to FILE as TOML instead of encoding"),
+        )
+        .arg(
+            Arg::new("max_length")
+                .long("max-length")
+                .value_name("N")
+                .help("Reject messages longer than N characters")
+                .value_parser(clap::value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("filter")
+                .long("filter")
+                .value_name("CLASS")
+                .help("Drop or convert input characters by class before encoding")
+                .value_parser(["letters", "letters-spaces", "uppercase"]),
+        )
+        .arg(
+            Arg::new("spell_numbers")
+                .long("spell-numbers")
+                .help("Spell out digits before encrypting (1 becomes QONE, Q becomes QQ); undone with -d")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("output_format")
+                .long("output-format")
+                .value_name("FORMAT")
+                .help("Print plain ciphertext or a JSON object with positions")
+                .value_parser(["text", "json"])
+                .default_value("text"),
+        )
+        .arg(
+            Arg::new("write")
+                .short('w')
+                .long("write")
+                .value_name("FILE")
+                .help("Write the output to FILE (or a named pipe) instead of stdout"),
+        )
+        .arg(
+            Arg::new("lint")
+                .long("lint")
+                .help("Report weaknesses in the configuration instead of encoding")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("lint_strict")
+                .long("lint-strict")
+                .help("Like --lint, but exit nonzero if any weakness is found")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("try_keys")
+                .long("try-keys")
+                .value_name("DIR")
+                .help("Decode with every rotor file in DIR and rank them by English score"),
+        )
+        .arg(
+            Arg::new("no_reflector")
+                .long("no-reflector")
+                .help("Pass the signal through the rotors once without the reflector (not reciprocal; decrypt with -d)")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with_all([
+                    "indicator",
+                    "ground_setting",
+                    "second_rotor_file",
+                    "stream",
+                    "recursive",
+                    "trace",
+                    "roundtrip",
+                    "turnover_report",
+                    "positions_log",
+                ]),
+        )
+        .arg(
+            Arg::new("turnover_report")
+                .long("turnover-report")
+                .help("Print how often the fast and middle rotors turned over to stderr")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with_all(["indicator", "ground_setting"]),
+        )
+        .arg(
+            Arg::new("export_vectors_csv")
+                .long("export-vectors-csv")
+                .value_name("FILE")
+                .help("Encrypt the message from 32 random start positions and write the results to FILE as CSV")
+                .conflicts_with_all(["second_rotor_file", "indicator", "no_reflector"]),
+        )
+        .arg(
+            Arg::new("avalanche")
+                .long("avalanche")
+                .value_name("INDEX")
+                .help("Encode the message and a copy with the character at INDEX changed, and compare")
+                .value_parser(clap::value_parser!(usize))
+                .conflicts_with_all(["second_rotor_file", "indicator", "no_reflector"]),
+        )
+        .arg(
+            Arg::new("trace")
+                .long("trace")
+                .help("Print each keypress with its output, the new positions and the rotors that stepped")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with_all(["second_rotor_file", "indicator"]),
+        )
+        .arg(
+            Arg::new("solve_plugs")
+                .long("solve-plugs")
+                .value_name("CRIB")
+                .help("Find plugboards that encrypt CRIB to the start of the message at these rotor settings")
+                .conflicts_with_all(["switch_plugboard_file", "second_rotor_file", "indicator"]),
+        )
+        .arg(
+            Arg::new("stream")
+                .long("stream")
+                .help("Encode stdin to stdout (or --write) with continuous stepping across lines")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with_all(["message", "second_rotor_file", "indicator"]),
+        )
+        .arg(
+            Arg::new("line_buffered")
+                .long("line-buffered")
+                .help("Flush streamed output after every line")
+                .action(clap::ArgAction::SetTrue)
+                .requires("stream"),
+        )
+        .arg(
+            Arg::new("period")
+                .long("period")
+                .help("Print the stepping period from the start positions instead of encoding")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("random_start")
+                .long("random-start")
+                .help("Pick a random message key and send it in the indicator")
+                .action(clap::ArgAction::SetTrue)
+                .requires("indicator_mode")
+                .conflicts_with("message_key"),
+        )
+        .arg(
+            Arg::new("recursive")
+                .long("recursive")
+                .value_names(["IN_DIR", "OUT_DIR"])
+                .num_args(2)
+                .help("Encode every file under IN_DIR into the same path under OUT_DIR"),
+        )
+        .arg(
+            Arg::new("passthrough")
+                .long("passthrough")
+                .help("Copy characters outside the alphabet through unchanged")
+                .action(clap::ArgAction::SetTrue)
+                .requires("recursive"),
+        )
+        .arg(
+            Arg::new("roundtrip")
+                .long("roundtrip")
+                .value_name("PLAINTEXT")
+                .help(
+                    "Encrypt PLAINTEXT, decrypt it again and fail unless it comes back unchanged",
+                ),
+        )
+        .arg(
+            Arg::new("manifest")
+                .long("manifest")
+                .value_name("FILE")
+                .help("Write the configuration used, without message text, to FILE as JSON"),
+        )
+        .arg(
+            Arg::new("state_file")
+                .long("state-file")
+                .value_name("FILE")
+                .help("Restore positions, notches, reflector and plugs from FILE, and save them after encoding"),
+        )
+        .arg(
+            Arg::new("verify_stepping")
+                .long("verify-stepping")
+                .help("Step through a full period and check the rotors return to the start positions")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("print_paths")
+                .long("print-paths")
+                .help("Print the resolved rotor and plugboard file paths to stderr")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("inspect")
+                .long("inspect")
+                .help("Print rotor wiring, positions, notches and presses to the next turnover instead of encoding")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("message")
+                .help("Message to encrypt/decrypt")
+                .required_unless_present_any([
+                    "generate",
+                    "generate_plugboard",
+                    "equivalent_to",
+                    "export_plugboard",
+                    "lint",
+                    "lint_strict",
+                    "stream",
+                    "convert",
+                    "period",
+                    "recursive",
+                    "inspect",
+                    "roundtrip",
+                    "verify_stepping",
+                ]),
+        )
+        .get_matches();
+
+    let mut generated_something = false;
+    let dry_run = matches.get_flag("dry_run");
+    let force = matches.get_flag("force");
+    let seed = match matches.get_one::<String>("seed_file") {
+        Some(seed_file) => match seed_from_file(seed_file) {
+            Ok(seed) => Some(seed),
+            Err(e) => {
+                eprintln!("Error reading seed file: {}", e);
+                process::exit(1);
+            }
+        },
+        None => matches.get_one::<u64>("seed").copied(),
+    };
+
+    if matches.get_flag("generate") && dry_run {
+        report_planned_write(matches.get_one::<String>("rotor_file").unwrap(), force);
+        generated_something = true;
+    } else if matches.get_flag("generate") {
+        let rotor_file = matches.get_one::<String>("rotor_file").unwrap();
+        let min_displacement = *matches.get_one::<u64>("min_displacement").unwrap() as usize;
+        let result = generate_rotors_seeded(
+            rotor_file,
+            seed,
+            matches.get_one::<u64>("reflector_seed").copied(),
+            min_displacement,
+            force,
+        )
+        .and_then(|rotor_state| {
+            if matches.get_flag("print_wirings") {
+                print!("{}", rotor_state_to_text(&rotor_state, DEFAULT_NOTCHES)?);
+            }
+            Ok(())
+        });
+        if let Err(e) = result {
+            eprintln!("Error generating rotors: {}", e);
+            process::exit(1);
+        }
+        generated_something = true;
+    }
+
+    if matches.get_flag("generate_plugboard") {
+        let plugboard_file = matches.get_one::<String>("plugboard_file");
+        let output_file = plugboard_file
+            .map(|s| s.as_str())
+            .unwrap_or(DEFAULT_PLUGBOARD_FILE);
+        if dry_run {
+            report_planned_write(output_file, force);
+        } else if let Err(e) = match matches.get_one::<u64>("random_plugs") {
+            Some(&count) => generate_random_plugboard(output_file, seed, count as usize, force),
+            None => generate_plugboard(output_file, force),
+        } {
+            eprintln!("Error generating plugboard: {}", e);
+            process::exit(1);
+        }
+        generated_something = true;
+    }
+
+    if generated_something {
+        return;
+    }
+
+    if let Some(output_file) = matches.get_one::<String>("convert") {
+        let rotor_file = matches.get_one::<String>("rotor_file").unwrap();
+        let result = EnigmaMachine::load_rotor_state(rotor_file).and_then(|rotor_state| {
+            let notches = EnigmaMachine::load_rotor_notches(rotor_file)?.unwrap_or(DEFAULT_NOTCHES);
+            if dry_run {
+                is_text_rotor_file(output_file).map(|_| ())
+            } else {
+                save_rotor_state(&rotor_state, notches, output_file)
+            }
+        });
+        if let Err(e) = result {
+            eprintln!("Error converting rotor file: {}", e);
+            process::exit(1);
+        }
+        if dry_run {
+            report_planned_write(output_file, true);
+        } else {
+            println!("Rotor configuration converted to: {}", output_file);
+        }
+        return;
+    }
+
+    let rotor_file = matches.get_one::<String>("rotor_file").unwrap();
+    let plugboard_files: Vec<&str> = matches
+        .get_many::<String>("plugboard_file")
+        .into_iter()
+        .flatten()
+        .map(|s| s.as_str())
+        .collect();
+
+    let ground_setting = matches.get_one::<String>("ground_setting");
+    let positions = ground_setting.unwrap_or(matches.get_one::<String>("positions").unwrap());
+
+    let from_command_line = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+    let config_file = matches.get_one::<String>("config");
+
+    if matches.get_flag("print_paths") {
+        let source = |id: &str, flag: &str, var: &str| match matches.value_source(id) {
+            Some(ValueSource::CommandLine) => flag.to_string(),
+            Some(ValueSource::EnvVariable) => var.to_string(),
+            _ => "default".to_string(),
+        };
+        if let Some(config_file) = config_file {
+            eprintln!("config file: {}", config_file);
+        }
+        if config_file.is_some() && !from_command_line("rotor_file") {
+            eprintln!("rotor file: (from --config)");
+        } else {
+            eprintln!(
+                "rotor file: {} ({})",
+                rotor_file,
+                source("rotor_file", "--rotor-file", "ENIGMA_ROTOR_FILE")
+            );
+        }
+        if config_file.is_some() && !from_command_line("plugboard_file") {
+            eprintln!("plugboard file: (from --config)");
+        } else {
+            for plugboard_file in &plugboard_files {
+                eprintln!(
+                    "plugboard file: {} ({}{})",
+                    plugboard_file,
+                    source(
+                        "plugboard_file",
+                        "--plugboard-file",
+                        "ENIGMA_PLUGBOARD_FILE"
+                    ),
+                    if Path::new(plugboard_file).exists() {
+                        ""
+                    } else {
+                        ", not found, so no plugs"
+                    }
+                );
+            }
+        }
+    }
+
+    let machine = match config_file {
+        Some(config_file) => EnigmaMachine::from_config(
+            config_file,
+            Some(rotor_file.as_str()).filter(|_| from_command_line("rotor_file")),
+            if from_command_line("plugboard_file") {
+                &plugboard_files
+            } else {
+                &[]
+            },
+            positions,
+        ),
+        None => EnigmaMachine::new(rotor_file, &plugboard_files, positions),
+    };
+
+    let mut enigma = match machine {
+        Ok(machine) => machine,
+        Err(e) => {
+            eprintln!("Error initializing Enigma machine: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let overrides = match MachineOverrides::from_matches(&matches) {
+        Ok(overrides) => overrides,
+        Err(e) => {
+            eprintln!("Error initializing Enigma machine: {}", e);
+            process::exit(1);
+        }
+    };
+    if let Err(e) = overrides.apply(&mut enigma) {
+        eprintln!("Error initializing Enigma machine: {}", e);
+        process::exit(1);
+    }
+
+    // Builds a further machine from another rotor file with the same
+    // --config, plugboard files and overrides as the main one.
+    let load_machine = |rotor_file: &str, positions: &str| -> Result<EnigmaMachine, EnigmaError> {
+        let mut machine = match config_file {
+            Some(config_file) => EnigmaMachine::from_config(
+                config_file,
+                Some(rotor_file),
+                if from_command_line("plugboard_file") {
+                    &plugboard_files
+                } else {
+                    &[]
+                },
+                positions,
+            ),
+            None => EnigmaMachine::new(rotor_file, &plugboard_files, positions),
+        }?;
+        overrides.apply(&mut machine)?;
+        Ok(machine)
+    };
+
+    let state_file = matches.get_one::<String>("state_file");
+    if let Some(state_file) = state_file
+        && Path::new(state_file).exists()
+    {
+        let result = std::fs::read_to_string(state_file)
+            .map_err(EnigmaError::from)
+            .and_then(|contents| Ok(toml::from_str::<MachineState>(&contents)?))
+            .and_then(|state| enigma.restore(&state));
+        if let Err(e) = result {
+            eprintln!("Error initializing Enigma machine: {}", e);
+            process::exit(1);
+        }
+    }
+
+    if matches.get_flag("lint") || matches.get_flag("lint_strict") {
+        let (warnings, notes) = enigma.lint();
+        for warning in &warnings {
+            eprintln!("warning: {}", warning);
+        }
+        for note in &notes {
+            eprintln!("note: {}", note);
+        }
+        if warnings.is_empty() {
+            println!("No weaknesses found");
+        } else if matches.get_flag("lint_strict") {
+            process::exit(1);
+        }
+        return;
+    }
+
+    let advance = *matches.get_one::<usize>("advance").unwrap();
+    enigma.advance(advance);
+
+    for setting in matches.get_many::<String>("set_rotor").unwrap_or_default() {
+        let result = match setting.split_once(':') {
+            Some((index, position)) => {
+                let mut chars = position.chars();
+                match (index.parse::<usize>(), chars.next(), chars.next()) {
+                    (Ok(index), Some(c), None) => enigma.set_rotor_position(index, c),
+                    _ => Err(EnigmaError::InvalidMessage(format!(
+                        "Invalid rotor setting '{}', expected INDEX:CHAR",
+                        setting
+                    ))),
+                }
+            }
+            None => Err(EnigmaError::InvalidMessage(format!(
+                "Invalid rotor setting '{}', expected INDEX:CHAR",
+                setting
+            ))),
+        };
+        if let Err(e) = result {
+            eprintln!("Error initializing Enigma machine: {}", e);
+            process::exit(1);
+        }
+    }
+
+    if matches.get_flag("inspect") {
+        print!("{}", enigma.inspect());
+        return;
+    }
+
+    if let Some(plaintext) = matches.get_one::<String>("roundtrip") {
+        match enigma.check_roundtrip(plaintext) {
+            Ok(None) => println!("Round trip OK"),
+            Ok(Some(index)) => {
+                eprintln!(
+                    "Round trip failed: decryption differs from the plaintext at character {}",
+                    index
+                );
+                process::exit(1);
+            }
+            Err(e) => {
+                eprintln!("Error processing message: {}", e);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if matches.get_flag("verify_stepping") {
+        match enigma.verify_stepping() {
+            Ok(period) => println!(
+                "Stepping OK: back at the start positions after {} presses",
+                period
+            ),
+            Err(e) => {
+                eprintln!("Stepping verification failed: {}", e);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if matches.get_flag("period") {
+        println!("{}", enigma.stepping_period());
+        return;
+    }
+
+    if matches.get_flag("explain") {
+        eprintln!("{}", enigma.explain());
+    }
+
+    if let Some(export_file) = matches.get_one::<String>("export_plugboard") {
+        if dry_run {
+            report_planned_write(export_file, true);
+            return;
+        }
+        if let Err(e) = write(export_file, enigma.plugboard.to_config().to_toml()) {
+            eprintln!("Error exporting plugboard: {}", e);
+            process::exit(1);
+        }
+        println!("Plugboard configuration exported to: {}", export_file);
+        return;
+    }
+
+    if let Some(other_file) = matches.get_one::<String>("equivalent_to") {
+        match load_machine(other_file, positions) {
+            Ok(mut other) => {
+                other.advance(advance);
+                if enigma.is_equivalent_to(&other) {
+                    println!("equivalent");
+                } else {
+                    println!("not equivalent");
+                    process::exit(1);
+                }
+            }
+            Err(e) => {
+                eprintln!("Error initializing comparison machine: {}", e);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Some(mut dirs) = matches.get_many::<String>("recursive") {
+        let input_dir = dirs.next().unwrap();
+        let output_dir = dirs.next().unwrap();
+        match check_tree_dirs(Path::new(input_dir), Path::new(output_dir)).and_then(|_| {
+            encode_tree(
+                &enigma,
+                Path::new(input_dir),
+                Path::new(output_dir),
+                matches.get_flag("passthrough"),
+                dry_run,
+            )
+        }) {
+            Ok(_) if dry_run => {}
+            Ok(count) => println!("Encoded {} file(s) into: {}", count, output_dir),
+            Err(e) => {
+                eprintln!("Error encoding directory: {}", e);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if matches.get_flag("stream") {
+        if dry_run {
+            if let Some(output_file) = matches.get_one::<String>("write") {
+                report_planned_write(output_file, true);
+            }
+            return;
+        }
+        install_interrupt_handler();
+        let input = InterruptibleInput {
+            inner: std::io::stdin().lock(),
+        };
+        let result = match matches.get_one::<String>("write") {
+            Some(output_file) => File::create(output_file)
+                .map_err(EnigmaError::from)
+                .and_then(|file| {
+                    enigma.encode_stream(input, file, matches.get_flag("line_buffered"))
+                }),
+            None => {
+                enigma.encode_stream(input, std::io::stdout(), matches.get_flag("line_buffered"))
+            }
+        };
+        if let Err(e) = result {
+            eprintln!("Error encoding stream: {}", e);
+            process::exit(1);
+        }
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            process::exit(INTERRUPTED_EXIT_CODE);
+        }
+        return;
+    }
+
+    let space_as = matches.get_one::<String>("space_as").map(|placeholder| {
+        let mut chars = placeholder.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) if !ALPHABET.contains(c) => c,
+            _ => {
+                eprintln!(
+                    "Error encoding message: {}",
+                    EnigmaError::InvalidMessage(
+                        "--space-as must be 1 character outside the alphabet".to_string()
+                    )
+                );
+                process::exit(1);
+            }
+        }
+    });
+
+    let message = matches.get_one::<String>("message").unwrap();
+    let message = if matches.get_flag("from_morse_groups") {
+        from_morse_groups(message)
+    } else {
+        message.clone()
+    };
+    let message = match space_as {
+        Some(placeholder) => message.replace(placeholder, " "),
+        None => message,
+    };
+    let spell_numbers_requested = matches.get_flag("spell_numbers");
+    let message = if spell_numbers_requested && !matches.get_flag("decrypt") {
+        spell_numbers(&message)
+    } else {
+        message
+    };
+    let message = match matches.get_one::<String>("filter") {
+        Some(filter) => apply_filter(&message, filter),
+        None => message,
+    };
+
+    if let Some(&max_length) = matches.get_one::<usize>("max_length")
+        && let Err(e) = check_message_length(&message, max_length)
+    {
+        eprintln!("Error encoding message: {}", e);
+        process::exit(1);
+    }
+
+    if let Some(csv_file) = matches.get_one::<String>("export_vectors_csv") {
+        let sampled = seed.is_none();
+        let seed = seed.unwrap_or_else(|| thread_rng().r#gen());
+        let result = export_vectors_csv(&enigma, &mut StdRng::seed_from_u64(seed), &message)
+            .and_then(|csv| write_unless_dry_run(csv_file, csv, dry_run));
+        if let Err(e) = result {
+            eprintln!("Error exporting vectors: {}", e);
+            process::exit(1);
+        }
+        if sampled {
+            eprintln!(
+                "Generated with seed {} (pass --seed {} to reproduce)",
+                seed, seed
+            );
+        }
+        return;
+    }
+
+    if let Some(&index) = matches.get_one::<usize>("avalanche") {
+        let start = enigma.positions();
+        let mut chars: Vec<char> = message.chars().collect();
+        let result = match chars.get(index).and_then(|&c| ALPHABET.find(c)) {
+            Some(position) => {
+                chars[index] = ALPHABET
+                    .chars()
+                    .nth((position + 1) % ALPHABET.len())
+                    .unwrap();
+                let modified: String = chars.into_iter().collect();
+                enigma.encode_message(&message).and_then(|original| {
+                    let changed = enigma.at_positions(&start)?.encode_message(&modified)?;
+                    Ok((original, changed))
+                })
+            }
+            None => Err(EnigmaError::InvalidMessage(format!(
+                "No alphabet character at index {}",
+                index
+            ))),
+        };
+        match result {
+            Ok((original, changed)) => {
+                let differing: Vec<usize> = original
+                    .chars()
+                    .zip(changed.chars())
+                    .enumerate()
+                    .filter_map(|(i, (a, b))| (a != b).then_some(i))
+                    .collect();
+                println!("{}", original);
+                println!("{}", changed);
+                println!(
+                    "{} of {} output characters differ, from index {}",
+                    differing.len(),
+                    original.chars().count(),
+                    differing.first().map_or("-".to_string(), |i| i.to_string())
+                );
+            }
+            Err(e) => {
+                eprintln!("Error encoding message: {}", e);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if matches.get_flag("trace") {
+        for c in message.chars() {
+            match enigma.encode_char_detailed(c) {
+                Ok((encoded, steps)) => {
+                    let stepped: Vec<&str> = [
+                        (steps.rotor1, "rotor1"),
+                        (steps.rotor2, "rotor2"),
+                        (steps.rotor3, "rotor3"),
+                    ]
+                    .iter()
+                    .filter_map(|&(stepped, name)| stepped.then_some(name))
+                    .collect();
+                    println!(
+                        "'{}' -> '{}'  {}  stepped: {}",
+                        c,
+                        encoded,
+                        enigma.positions(),
+                        stepped.join(", ")
+                    );
+                }
+                Err(e) => {
+                    eprintln!("Error encoding message: {}", e);
+                    process::exit(1);
+                }
+            }
+        }
+        return;
+    }
+
+    if let Some(crib) = matches.get_one::<String>("solve_plugs") {
+        match enigma.solve_plugs(crib, &message) {
+            Ok(solutions) => {
+                for pairs in &solutions {
+                    let pairs: Vec<String> = pairs
+                        .iter()
+                        .map(|&(a, b)| format!("\"{}{}\"", a, b))
+                        .collect();
+                    println!("pairs = [{}]", pairs.join(", "));
+                }
+                eprintln!("{} plugboard(s) consistent with the crib", solutions.len());
+            }
+            Err(e) => {
+                eprintln!("Error solving plugboard: {}", e);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Some(dir) = matches.get_one::<String>("try_keys") {
+        match try_keys(dir, |path| load_machine(path, positions), &message) {
+            Ok(results) => {
+                for (path, score, decoded) in results {
+                    println!("{:.4}  {}  {}", score, path, decoded);
+                }
+            }
+            Err(e) => {
+                eprintln!("Error trying keys: {}", e);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let second = matches
+        .get_one::<String>("second_rotor_file")
+        .map(|second_file| {
+            let second_positions = matches.get_one::<String>("second_positions").unwrap();
+            match load_machine(second_file, second_positions) {
+                Ok(machine) => machine,
+                Err(e) => {
+                    eprintln!("Error initializing second Enigma machine: {}", e);
+                    process::exit(1);
+                }
+            }
+        });
+
+    let start_positions = enigma.positions();
+
+    let positions_log_file = matches.get_one::<String>("positions_log");
+    let mut positions_log = Vec::new();
+    // A rotor turns over on a keypress when it was on its notch beforehand
+    // and moved off it; a keypress skipped by --no-initial-step moves nothing.
+    // The left rotor has no neighbour to turn over, so it is not counted.
+    let permutations_file = matches.get_one::<String>("dump_permutations");
+    let mut permutations = Vec::new();
+    let mut turnovers = [0usize; 2];
+    let mut previous = (enigma.rotor1.position, enigma.rotor2.position);
+    let observer = |machine: &EnigmaMachine| {
+        if positions_log_file.is_some() {
+            positions_log.push(machine.positions());
+        }
+        if permutations_file.is_some() {
+            permutations.push(machine.permutation());
+        }
+        if previous.0 == machine.rotor1.notch_position && previous.0 != machine.rotor1.position {
+            turnovers[0] += 1;
+        }
+        if previous.1 == machine.rotor2.notch_position && previous.1 != machine.rotor2.position {
+            turnovers[1] += 1;
+        }
+        previous = (machine.rotor1.position, machine.rotor2.position);
+    };
+
+    let result = match second {
+        _ if matches.get_flag("no_reflector") => {
+            enigma.encode_message_one_way(&message, matches.get_flag("decrypt"))
+        }
+        _ if matches.get_flag("indicator") || ground_setting.is_some() => {
+            if matches.get_flag("decrypt") {
+                enigma.decode_with_indicator(&message)
+            } else {
+                let random_key = matches.get_flag("random_start")
+                    || (ground_setting.is_some() && !matches.contains_id("message_key"));
+                let message_key = if random_key {
+                    Some(match seed {
+                        Some(seed) => random_positions(&mut StdRng::seed_from_u64(seed)),
+                        None => random_positions(&mut thread_rng()),
+                    })
+                } else {
+                    matches.get_one::<String>("message_key").cloned()
+                };
+                match message_key {
+                    Some(message_key) => enigma.encode_with_indicator(&message_key, &message),
+                    None => Err(EnigmaError::InvalidMessage(
+                        "--indicator requires --message-key or --random-start when encrypting"
+                            .to_string(),
+                    )),
+                }
+            }
+        }
+        Some(mut second) if matches.get_flag("decrypt") => second
+            .encode_message(&message)
+            .and_then(|inner| enigma.encode_message_observed(&inner, observer)),
+        Some(mut second) => enigma
+            .encode_message_observed(&message, observer)
+            .and_then(|inner| second.encode_message(&inner)),
+        None => enigma.encode_message_observed(&message, observer),
+    };
+
+    if matches.get_flag("turnover_report") && result.is_ok() {
+        eprintln!("turnovers rotor1={} rotor2={}", turnovers[0], turnovers[1]);
+    }
+
+    if let Some(log_file) = positions_log_file {
+        let contents: String = positions_log
+            .iter()
+            .map(|positions| format!("{}\n", positions))
+            .collect();
+        if let Err(e) = write_unless_dry_run(log_file, contents, dry_run) {
+            eprintln!("Error writing positions log: {}", e);
+            process::exit(1);
+        }
+    }
+
+    if let Some(permutations_file) = permutations_file {
+        let contents: String = permutations
+            .iter()
+            .map(|permutation| format!("{}\n", permutation))
+            .collect();
+        if let Err(e) = write_unless_dry_run(permutations_file, contents, dry_run) {
+            eprintln!("Error writing permutations: {}", e);
+            process::exit(1);
+        }
+    }
+
+    if let Some(state_file) = state_file
+        && result.is_ok()
+    {
+        let saved = toml::to_string(&enigma.snapshot())
+            .map_err(EnigmaError::from)
+            .and_then(|contents| write_unless_dry_run(state_file, contents, dry_run));
+        if let Err(e) = saved {
+            eprintln!("Error writing state file: {}", e);
+            process::exit(1);
+        }
+    }
+
+    if let Some(manifest_file) = matches.get_one::<String>("manifest")
+        && result.is_ok()
+    {
+        let mut modes: Vec<&str> = [
+            "decrypt",
+            "indicator",
+            "random_start",
+            "no_reflector",
+            "no_initial_step",
+            "spell_numbers",
+            "morse_groups",
+            "from_morse_groups",
+        ]
+        .into_iter()
+        .filter(|&id| matches.get_flag(id))
+        .collect();
+        if ground_setting.is_some() {
+            modes.push("ground_setting");
+        }
+        if matches.contains_id("second_rotor_file") {
+            modes.push("layered");
+        }
+        if enigma.switched_plugboard.is_some() {
+            modes.push("switched_plugboard");
+        }
+        if enigma.plugboard_mode != PlugboardMode::Both {
+            modes.push("plugboard_mode=entry-only");
+        }
+        let filter_mode = matches
+            .get_one::<String>("filter")
+            .map(|filter| format!("filter={}", filter));
+        modes.extend(filter_mode.as_deref());
+        if let Err(e) = write_unless_dry_run(
+            manifest_file,
+            enigma.manifest(&start_positions, &modes) + "\n",
+            dry_run,
+        ) {
+            eprintln!("Error writing manifest: {}", e);
+            process::exit(1);
+        }
+    }
+
+    let result = if spell_numbers_requested && matches.get_flag("decrypt") {
+        result.and_then(|result| unspell_numbers(&result))
+    } else {
+        result
+    };
+
+    match result {
+        Ok(result) => {
+            let result = match matches.get_one::<usize>("preview") {
+                Some(&n) => result.chars().take(n).collect(),
+                None => result,
+            };
+            // The placeholder goes in before grouping, so the spaces between
+            // groups stay spaces; input undoes it after ungrouping.
+            let output = match space_as {
+                Some(placeholder) => result.replace(' ', &placeholder.to_string()),
+                None => result.clone(),
+            };
+            let output = if matches.get_flag("morse_groups") {
+                to_morse_groups(&output)
+            } else {
+                output
+            };
+            let output = match matches.get_one::<String>("output_format").unwrap().as_str() {
+                "json" => format!(
+                    "{{\"ciphertext\":{},\"start_positions\":{},\"end_positions\":{},\"length\":{}}}",
+                    json_string(&output),
+                    json_string(&start_positions),
+                    json_string(&enigma.positions()),
+                    result.chars().count()
+                ),
+                _ => output,
+            };
+            let written = match matches.get_one::<String>("write") {
+                Some(output_file) if dry_run => {
+                    report_planned_write(output_file, true);
+                    Ok(())
+                }
+                Some(output_file) => write_output(output_file, &output),
+                None => {
+                    println!("{}", output);
+                    Ok(())
+                }
+            };
+            if let Err(e) = written {
+                eprintln!("Error writing output: {}", e);
+                process::exit(1);
+            }
+            if matches.get_flag("status_line") {
+                eprintln!(
+                    "{}",
+                    status_line(&message, &result, &start_positions, &enigma)
+                );
+            }
+        }
+        Err(e) => {
+            eprintln!("Error encoding message: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seeded_rotor_state(seed: u64) -> RotorState {
+        random_rotor_state(&mut StdRng::seed_from_u64(seed), 0)
+    }
+
+    /// A machine with seeded rotors, default notches and an empty plugboard.
+    fn seeded_machine(seed: u64, positions: &str) -> EnigmaMachine {
+        EnigmaMachine::from_parts(
+            seeded_rotor_state(seed),
+            DEFAULT_NOTCHES,
+            Plugboard::new(),
+            positions,
+        )
+        .unwrap()
+    }
+
+    /// Creates an empty scratch directory for one test.
+    fn scratch_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("enigma-{}-{}", name, process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn scratch_file(dir: &Path, name: &str) -> String {
+        dir.join(name).to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn rotor_backward_inverts_forward_at_every_position() {
+        let mut rotor = Rotor::new(seeded_rotor_state(1).rotor1, 0).unwrap();
+
+        for position in 0..ALPHABET.len() {
+            rotor.position = position;
+            for input in 0..ALPHABET.len() {
+                assert_eq!(
+                    rotor.encode_backward(rotor.encode_forward(input)),
+                    input,
+                    "position {}",
+                    position
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn plugboard_swap_is_self_inverse_for_every_pair_count() {
+        for count in 0..=ALPHABET.len() / 2 {
+            let mut rng = StdRng::seed_from_u64(count as u64);
+            let plugboard = Plugboard::from_pairs(random_pairs(&mut rng, count)).unwrap();
+
+            assert_eq!(plugboard.pairs().len(), count);
+            for c in ALPHABET.chars() {
+                assert_eq!(plugboard.swap(plugboard.swap(c)), c, "{} pairs", count);
+            }
+        }
+    }
+
+    #[test]
+    fn rotor_wraps_from_last_index() {
+        let last = ALPHABET.len() - 1;
+        let mut rotor = Rotor::new(seeded_rotor_state(1).rotor1, last).unwrap();
+        rotor.position = last;
+        assert!(rotor.at_notch());
+
+        let wired = ALPHABET
+            .find(rotor.wiring.chars().nth(last).unwrap())
+            .unwrap();
+        assert_eq!(rotor.encode_forward(0), (wired + 1) % ALPHABET.len());
+        for input in 0..ALPHABET.len() {
+            assert_eq!(rotor.encode_backward(rotor.encode_forward(input)), input);
+        }
+
+        rotor.step();
+        assert_eq!(rotor.position, 0);
+        assert!(!rotor.at_notch());
+    }
+
+    #[test]
+    fn keypress_wraps_every_rotor_from_last_index() {
+        let last = ALPHABET.len() - 1;
+        let mut machine =
+            EnigmaMachine::from_parts(seeded_rotor_state(1), [last; 3], Plugboard::new(), "   ")
+                .unwrap();
+
+        let steps = machine.press_key();
+        assert_eq!(
+            steps,
+            StepInfo {
+                rotor1: true,
+                rotor2: true,
+                rotor3: true,
+            }
+        );
+        assert_eq!(machine.positions(), "aaa");
+
+        let mut machine = EnigmaMachine::from_parts(
+            seeded_rotor_state(1),
+            DEFAULT_NOTCHES,
+            Plugboard::new(),
+            "   ",
+        )
+        .unwrap();
+        let ciphertext = machine.encode_message("Hello World").unwrap();
+        assert_eq!(machine.positions(), "k  ");
+        assert_eq!(
+            machine
+                .decryptor("   ")
+                .unwrap()
+                .encode_message(&ciphertext)
+                .unwrap(),
+            "Hello World"
+        );
+    }
+
+    #[test]
+    fn default_reflector_wiring_is_pinned() {
+        let reflector = Reflector::new();
+        assert_eq!(
+            reflector.wiring,
+            "badcfehgjilknmporqtsvuxwzyBADCFEHGJILKNMPORQTSVUXWZY "
+        );
+
+        for i in 0..ALPHABET.len() {
+            assert_eq!(reflector.reflect(reflector.reflect(i)), i);
+            assert_eq!(reflector.reflect(i) == i, i == ALPHABET.find(' ').unwrap());
+        }
+    }
+
+    #[test]
+    fn default_configuration_returns_to_start_after_one_period() {
+        let machine = EnigmaMachine::from_parts(
+            seeded_rotor_state(1),
+            DEFAULT_NOTCHES,
+            Plugboard::new(),
+            "aaa",
+        )
+        .unwrap();
+        let len = ALPHABET.len();
+
+        assert_eq!(machine.verify_stepping().unwrap(), len * (len - 1) * len);
+    }
+
+    #[test]
+    fn middle_rotor_steps_once_when_both_rotors_sit_on_notches() {
+        let mut machine = EnigmaMachine::from_parts(
+            seeded_rotor_state(1),
+            DEFAULT_NOTCHES,
+            Plugboard::new(),
+            "qea",
+        )
+        .unwrap();
+
+        machine.press_key();
+        assert_eq!(machine.positions(), "rfb");
+    }
+
+    #[test]
+    fn out_of_range_notch_is_rejected() {
+        let len = ALPHABET.len();
+
+        assert!(matches!(
+            Rotor::new(seeded_rotor_state(1).rotor1, len),
+            Err(EnigmaError::InvalidNotchPosition(notch)) if notch == len
+        ));
+        assert!(matches!(
+            EnigmaMachine::from_parts(seeded_rotor_state(1), [0, 0, len], Plugboard::new(), "aaa"),
+            Err(EnigmaError::InvalidNotchPosition(notch)) if notch == len
+        ));
+    }
+
+    #[test]
+    fn plugboard_survives_export_and_reload() {
+        let config: PlugboardConfig = toml::from_str(r#"pairs = ["ab", "CD", "X "]"#).unwrap();
+        let plugboard = Plugboard::from_pairs(config.pairs).unwrap();
+
+        let exported = plugboard.to_config().to_toml();
+        let config: PlugboardConfig = toml::from_str(&exported).unwrap();
+        let reloaded = Plugboard::from_pairs(config.pairs).unwrap();
+
+        assert_eq!(reloaded.pairs(), plugboard.pairs());
+        assert_eq!(reloaded.pairs(), vec![('a', 'b'), ('C', 'D'), ('X', ' ')]);
+    }
+
+    #[test]
+    fn solve_plugs_recovers_a_small_plugboard() {
+        let pairs = vec!["ab".to_string(), "cd".to_string(), "xy".to_string()];
+        let mut machine = EnigmaMachine::from_parts(
+            seeded_rotor_state(1),
+            DEFAULT_NOTCHES,
+            Plugboard::from_pairs(pairs).unwrap(),
+            "xyz",
+        )
+        .unwrap();
+        let crib = "the quick brown fox jumps over the lazy dog and the cat";
+        let ciphertext = machine.encode_message(crib).unwrap();
+
+        let unplugged = EnigmaMachine::from_parts(
+            seeded_rotor_state(1),
+            DEFAULT_NOTCHES,
+            Plugboard::new(),
+            "xyz",
+        )
+        .unwrap();
+        let solutions = unplugged.solve_plugs(crib, &ciphertext).unwrap();
+
+        assert!(solutions.iter().any(|solution| {
+            [('a', 'b'), ('c', 'd'), ('x', 'y')]
+                .iter()
+                .all(|pair| solution.contains(pair))
+        }));
+    }
+
+    #[test]
+    fn plugboard_files_merge_and_report_conflicts() {
+        let dir = std::env::temp_dir().join(format!("enigma-merge-{}", process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = |name: &str, pairs: &str| {
+            let path = dir.join(name).to_string_lossy().into_owned();
+            write(&path, format!("pairs = [{}]\n", pairs)).unwrap();
+            path
+        };
+        let base = file("base.toml", r#""ab", "CD""#);
+        let session = file("session.toml", r#""xy""#);
+        let clashing = file("clashing.toml", r#""bz""#);
+
+        let merged = EnigmaMachine::load_optional_plugboard(&[&base, &session]).unwrap();
+        assert_eq!(merged.pairs(), vec![('a', 'b'), ('x', 'y'), ('C', 'D')]);
+
+        let message = match EnigmaMachine::load_optional_plugboard(&[&base, &clashing]) {
+            Err(e) => e.to_string(),
+            Ok(_) => panic!("conflicting plugboard files were merged"),
+        };
+        assert!(message.contains("'b'"), "{}", message);
+        assert!(message.contains(&base), "{}", message);
+        assert!(message.contains(&clashing), "{}", message);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotor_files_convert_from_bincode_to_text_and_back() {
+        let dir = scratch_dir("convert");
+        let mut rotor_state = seeded_rotor_state(1);
+        rotor_state.reflector = Some(Reflector::random(&mut StdRng::seed_from_u64(2)).wiring);
+        let notches = [1, 2, 3];
+
+        let binary = scratch_file(&dir, "key.enigma");
+        let text = scratch_file(&dir, "key.toml");
+        let binary_again = scratch_file(&dir, "again.enigma");
+        save_rotor_state(&rotor_state, DEFAULT_NOTCHES, &binary).unwrap();
+        let loaded = EnigmaMachine::load_rotor_state(&binary).unwrap();
+        save_rotor_state(&loaded, notches, &text).unwrap();
+        let loaded = EnigmaMachine::load_rotor_state(&text).unwrap();
+        assert_eq!(
+            EnigmaMachine::load_rotor_notches(&text).unwrap(),
+            Some(notches)
+        );
+        save_rotor_state(&loaded, notches, &binary_again).unwrap();
+        let loaded = EnigmaMachine::load_rotor_state(&binary_again).unwrap();
+
+        assert_eq!(loaded.rotor1, rotor_state.rotor1);
+        assert_eq!(loaded.rotor2, rotor_state.rotor2);
+        assert_eq!(loaded.rotor3, rotor_state.rotor3);
+        assert_eq!(loaded.reflector, rotor_state.reflector);
+
+        let json = scratch_file(&dir, "key.json");
+        assert!(matches!(
+            save_rotor_state(&rotor_state, notches, &json),
+            Err(EnigmaError::FileError(_))
+        ));
+        assert!(!Path::new(&json).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn nested_tree_round_trips_and_rejects_output_inside_input() {
+        let dir = scratch_dir("tree");
+        let input = dir.join("in");
+        std::fs::create_dir_all(input.join("sub/deeper")).unwrap();
+        write(input.join("top.txt"), "Hello World\nsecond line\n").unwrap();
+        write(input.join("sub/deeper/nested.txt"), "Nested File\n").unwrap();
+        let machine = EnigmaMachine::from_parts(
+            seeded_rotor_state(1),
+            DEFAULT_NOTCHES,
+            Plugboard::new(),
+            "xyz",
+        )
+        .unwrap();
+
+        let encrypted = dir.join("enc");
+        let decrypted = dir.join("dec");
+        assert_eq!(
+            encode_tree(&machine, &input, &encrypted, false, false).unwrap(),
+            2
+        );
+        assert_eq!(
+            encode_tree(&machine, &encrypted, &decrypted, false, false).unwrap(),
+            2
+        );
+        assert_ne!(
+            std::fs::read_to_string(encrypted.join("top.txt")).unwrap(),
+            "Hello World\nsecond line\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(decrypted.join("top.txt")).unwrap(),
+            "Hello World\nsecond line\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(decrypted.join("sub/deeper/nested.txt")).unwrap(),
+            "Nested File\n"
+        );
+
+        assert!(check_tree_dirs(&input, &encrypted).is_ok());
+        assert!(check_tree_dirs(&input, &input.join("out")).is_err());
+        assert!(check_tree_dirs(&input, &input).is_err());
+        assert!(!input.join("out").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn machines_differing_only_in_the_left_notch_are_equivalent() {
+        let machine = |notches| {
+            EnigmaMachine::from_parts(seeded_rotor_state(1), notches, Plugboard::new(), "xyz")
+                .unwrap()
+        };
+
+        // Nothing sits to the left of the left rotor, so its notch never
+        // changes the output.
+        assert!(machine([16, 4, 21]).is_equivalent_to(&machine([16, 4, 0])));
+        assert!(!machine([16, 4, 21]).is_equivalent_to(&machine([16, 5, 21])));
+    }
+
+    #[test]
+    fn try_keys_ranks_the_correct_key_first() {
+        let dir = scratch_dir("try-keys");
+        for seed in 1..=5 {
+            let path = scratch_file(&dir, &format!("key{}.enigma", seed));
+            save_rotor_state(&seeded_rotor_state(seed), DEFAULT_NOTCHES, &path).unwrap();
+        }
+        let plugs = Plugboard::from_pairs(vec!["ab".to_string(), "ef".to_string()]).unwrap();
+        let load_machine = |path: &str| {
+            let mut machine = EnigmaMachine::new(path, &[], "xyz")?;
+            machine.plugboard = plugs.clone();
+            Ok(machine)
+        };
+        let plaintext = "meet me at the old bridge at noon and bring the documents with you";
+        let ciphertext = load_machine(&scratch_file(&dir, "key3.enigma"))
+            .unwrap()
+            .encode_message(plaintext)
+            .unwrap();
+
+        let results = try_keys(&dir.to_string_lossy(), load_machine, &ciphertext).unwrap();
+
+        assert_eq!(results.len(), 5);
+        assert_eq!(results[0].0, scratch_file(&dir, "key3.enigma"));
+        assert_eq!(results[0].2, plaintext);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Records what had been written at each flush.
+    struct FlushLog {
+        written: Vec<u8>,
+        flushes: Vec<String>,
+    }
+
+    impl Write for FlushLog {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.flushes
+                .push(String::from_utf8(self.written.clone()).unwrap());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn stream_output_is_flushed_per_line_and_keeps_line_endings() {
+        let machine = || {
+            EnigmaMachine::from_parts(
+                seeded_rotor_state(7),
+                DEFAULT_NOTCHES,
+                Plugboard::new(),
+                "abc",
+            )
+            .unwrap()
+        };
+        let input = "first line\r\nsecond\nlast";
+        let mut reference = machine();
+        let expected = format!(
+            "{}\r\n{}\n{}",
+            reference.encode_message("first line").unwrap(),
+            reference.encode_message("second").unwrap(),
+            reference.encode_message("last").unwrap(),
+        );
+
+        let mut buffered = Vec::new();
+        machine()
+            .encode_stream(input.as_bytes(), &mut buffered, false)
+            .unwrap();
+        assert_eq!(String::from_utf8(buffered).unwrap(), expected);
+
+        let mut log = FlushLog {
+            written: Vec::new(),
+            flushes: Vec::new(),
+        };
+        machine()
+            .encode_stream(input.as_bytes(), &mut log, true)
+            .unwrap();
+        assert_eq!(String::from_utf8(log.written).unwrap(), expected);
+        let lines: Vec<&str> = expected.split_inclusive('\n').collect();
+        assert_eq!(log.flushes[0], lines[0]);
+        assert_eq!(log.flushes[1], lines[..2].concat());
+        assert_eq!(log.flushes.last().unwrap(), &expected);
+    }
+
+    #[test]
+    fn morse_groups_round_trip_but_keep_letter_case() {
+        let machine = || {
+            EnigmaMachine::from_parts(
+                seeded_rotor_state(3),
+                DEFAULT_NOTCHES,
+                Plugboard::new(),
+                "qrs",
+            )
+            .unwrap()
+        };
+        let plaintext = "Send More Troops";
+        let ciphertext = machine().encode_message(plaintext).unwrap();
+        let groups = to_morse_groups(&ciphertext);
+
+        assert!(
+            groups
+                .split(' ')
+                .all(|group| group.chars().count() <= MORSE_GROUP_SIZE)
+        );
+        assert_eq!(from_morse_groups(&groups), ciphertext);
+        assert_eq!(
+            machine()
+                .encode_message(&from_morse_groups(&groups))
+                .unwrap(),
+            plaintext
+        );
+
+        // Morse has no letter case; groups received in one case decode wrongly.
+        let received = from_morse_groups(&groups.to_lowercase());
+        assert_ne!(received, ciphertext);
+        assert_ne!(machine().encode_message(&received).unwrap(), plaintext);
+    }
+
+    #[test]
+    fn dumped_permutations_are_involutions_with_one_fixed_point() {
+        let plugboard = Plugboard::from_pairs(vec!["ab".to_string(), "xy".to_string()]).unwrap();
+        let mut machine =
+            EnigmaMachine::from_parts(seeded_rotor_state(5), DEFAULT_NOTCHES, plugboard, "abc")
+                .unwrap();
+
+        for _ in 0..200 {
+            machine.press_key();
+            let row: Vec<char> = machine.permutation().chars().collect();
+            assert_eq!(row.len(), ALPHABET.len());
+
+            let mut fixed_points = 0;
+            for (i, c) in ALPHABET.chars().enumerate() {
+                let image = ALPHABET.find(row[i]).unwrap();
+                assert_eq!(
+                    row[image],
+                    c,
+                    "not an involution at {}",
+                    machine.positions()
+                );
+                if row[i] == c {
+                    fixed_points += 1;
+                }
+            }
+            assert_eq!(fixed_points, 1, "at {}", machine.positions());
+        }
+    }
+
+    #[test]
+    fn keys_encode_to_themselves_only_through_the_reflector_fixed_point() {
+        let plugboard = Plugboard::from_pairs(vec!["ab".to_string(), "cd".to_string()]).unwrap();
+        let base =
+            EnigmaMachine::from_parts(seeded_rotor_state(9), DEFAULT_NOTCHES, plugboard, "aaa")
+                .unwrap();
+        let mut rng = StdRng::seed_from_u64(9);
 
-    let file = File::create(output_file)?;
-    let writer = BufWriter::new(file);
+        for _ in 0..50 {
+            let start = random_positions(&mut rng);
+            for c in ALPHABET.chars() {
+                let mut machine = base.clone();
+                machine.set_positions(&start).unwrap();
+                let encoded = machine.encode_char(c).unwrap();
 
-    bincode::serialize_into(writer, &rotor_state)?;
+                let mut signal = ALPHABET.find(machine.plugboard.swap(c)).unwrap();
+                signal = machine.entry_wheel.forward(signal);
+                signal = machine.rotor1.encode_forward(signal);
+                signal = machine.rotor2.encode_forward(signal);
+                signal = machine.rotor3.encode_forward(signal);
+                let at_fixed_point = machine.reflector.reflect(signal) == signal;
 
-    println!("Rotor configuration saved to: {}", output_file);
-    Ok(())
-}
+                assert_eq!(encoded == c, at_fixed_point, "{:?} at {}", c, start);
+            }
+        }
+    }
 
-fn generate_plugboard(output_file: &str) -> Result<(), EnigmaError> {
-    let plugboard_content = r#"
-# Enigma Plugboard Configuration
-# Each pair swaps two characters bidirectionally
-# Use two-character strings like "ab", "CD", "X ", etc.
+    #[test]
+    fn advance_matches_encoding_dummy_characters() {
+        let machine = || seeded_machine(2, "aYz");
 
-pairs = [
-    # "ab",  # a <-> b
-    # "CD",  # C <-> D
-    # "X ",  # X <-> space
-]
-"#;
+        for k in [0, 1, 52, 53 * 53 + 7] {
+            let mut advanced = machine();
+            advanced.advance(k);
+            let mut typed = machine();
+            for _ in 0..k {
+                typed.encode_char('x').unwrap();
+            }
 
-    write(output_file, plugboard_content)?;
-    println!("Plugboard configuration generated at: {}", output_file);
-    Ok(())
-}
+            assert_eq!(advanced.positions(), typed.positions(), "k = {}", k);
+            assert_eq!(
+                advanced.encode_message("after the skip").unwrap(),
+                typed.encode_message("after the skip").unwrap(),
+                "k = {}",
+                k
+            );
+        }
+    }
 
-fn main() {
-    let matches = Command::new("enigma")
-        .version("3.0")
-        .author("Definitely not the Nazis")
-        .about("A proper WWII Enigma machine cipher with rotors and plugboard.")
-        .arg(
-            Arg::new("generate")
-                .short('g')
-                .long("generate")
-                .help("Generate new rotor configuration")
-                .action(clap::ArgAction::SetTrue),
-        )
-        .arg(
-            Arg::new("generate_plugboard")
-                .short('p')
-                .long("generate-plugboard")
-                .help("Generate plugboard configuration file")
-                .action(clap::ArgAction::SetTrue),
-        )
-        .arg(
-            Arg::new("rotor_file")
-                .short('r')
-                .long("rotor-file")
-                .value_name("FILE")
-                .help("Path to rotor configuration file")
-                .default_value(DEFAULT_ROTOR_FILE),
+    #[test]
+    fn status_line_reports_lengths_positions_and_plugboard() {
+        let mut machine = seeded_machine(4, "abc");
+        let output = machine.encode_message("hello world").unwrap();
+        assert_eq!(
+            status_line("hello world", &output, "abc", &machine),
+            "status input_len=11 output_len=11 start=\"abc\" end=\"lbc\" plugboard=off"
+        );
+
+        machine.plugboard = Plugboard::from_pairs(vec!["ab".to_string()]).unwrap();
+        assert!(
+            status_line("x", "y", "a b", &machine)
+                .ends_with("start=\"a b\" end=\"lbc\" plugboard=on")
+        );
+    }
+
+    #[test]
+    fn cycle_notation_wiring_matches_the_full_string() {
+        let full: String = ALPHABET
+            .chars()
+            .map(|c| match c {
+                'a' => 'b',
+                'b' => 'c',
+                'c' => 'a',
+                'X' => ' ',
+                ' ' => 'X',
+                other => other,
+            })
+            .collect();
+        assert_eq!(parse_wiring("(abc)(X )").unwrap(), full);
+        assert_eq!(parse_wiring(&full).unwrap(), full);
+
+        let with_rotor1 = |wiring: String| {
+            let mut state = seeded_rotor_state(6);
+            state.rotor1 = wiring;
+            EnigmaMachine::from_parts(state, DEFAULT_NOTCHES, Plugboard::new(), "abc")
+                .unwrap()
+                .encode_message("cycle notation test")
+                .unwrap()
+        };
+        assert_eq!(
+            with_rotor1(parse_wiring("(abc)(X )").unwrap()),
+            with_rotor1(full)
+        );
+
+        assert!(parse_cycle_notation("(abc)(ad)").is_err());
+        assert!(parse_cycle_notation("(abc").is_err());
+        assert!(parse_cycle_notation("(ab1)").is_err());
+    }
+
+    #[test]
+    fn reflector_position_changes_ciphertext_and_round_trips() {
+        let plaintext = "reflector offsets";
+        let with_reflector_at = |position: char| {
+            let mut machine = seeded_machine(8, "abc");
+            machine.reflector = Reflector::random(&mut StdRng::seed_from_u64(8));
+            machine.reflector.set_position(position).unwrap();
+            machine
+        };
+
+        let mut ciphertexts = Vec::new();
+        for position in ['a', 'b', 'Q', ' '] {
+            let ciphertext = with_reflector_at(position)
+                .encode_message(plaintext)
+                .unwrap();
+            assert_eq!(
+                with_reflector_at(position)
+                    .encode_message(&ciphertext)
+                    .unwrap(),
+                plaintext
+            );
+            assert!(!ciphertexts.contains(&ciphertext), "{:?}", position);
+            ciphertexts.push(ciphertext);
+        }
+
+        assert!(Reflector::new().set_position('0').is_err());
+    }
+
+    #[test]
+    fn combined_config_loads_and_separate_files_override_it() {
+        let dir = scratch_dir("combined-config");
+        let state = seeded_rotor_state(10);
+        let config = scratch_file(&dir, "machine.toml");
+        write(
+            &config,
+            format!(
+                "rotor1 = {:?}\nrotor2 = {:?}\nrotor3 = {:?}\nnotches = \"bcd\"\n\
+                 reflector_position = \"c\"\npairs = [\"ab\", \"xy\"]\n",
+                state.rotor1, state.rotor2, state.rotor3
+            ),
         )
-        .arg(
-            Arg::new("plugboard_file")
-                .short('b')
-                .long("plugboard-file")
-                .value_name("FILE")
-                .help("Path to plugboard configuration file")
-                .default_value(DEFAULT_PLUGBOARD_FILE),
+        .unwrap();
+        let plaintext = "combined configuration";
+
+        let mut expected = EnigmaMachine::from_parts(
+            state,
+            parse_notches("bcd").unwrap(),
+            Plugboard::from_pairs(vec!["ab".to_string(), "xy".to_string()]).unwrap(),
+            "abc",
         )
-        .arg(
-            Arg::new("positions")
-                .short('s')
-                .long("start-positions")
-                .value_name("POSITIONS")
-                .help("Initial rotor positions (3 chars)")
-                .default_value("aaa"),
+        .unwrap();
+        expected.reflector.set_position('c').unwrap();
+        assert_eq!(
+            EnigmaMachine::from_config(&config, None, &[], "abc")
+                .unwrap()
+                .encode_message(plaintext)
+                .unwrap(),
+            expected.encode_message(plaintext).unwrap()
+        );
+
+        let rotor_file = scratch_file(&dir, "other.enigma");
+        save_rotor_state(&seeded_rotor_state(11), DEFAULT_NOTCHES, &rotor_file).unwrap();
+        let plugboard_file = scratch_file(&dir, "plugboard.toml");
+        let pairs = vec!["cd".to_string()];
+        write(
+            &plugboard_file,
+            PlugboardConfig {
+                pairs: pairs.clone(),
+            }
+            .to_toml(),
         )
-        .arg(
-            Arg::new("message")
-                .help("Message to encrypt/decrypt")
-                .required_unless_present_any(["generate", "generate_plugboard"]),
+        .unwrap();
+
+        let mut expected = EnigmaMachine::from_parts(
+            seeded_rotor_state(11),
+            parse_notches("bcd").unwrap(),
+            Plugboard::from_pairs(pairs).unwrap(),
+            "abc",
         )
-        .get_matches();
+        .unwrap();
+        expected.reflector.set_position('c').unwrap();
+        assert_eq!(
+            EnigmaMachine::from_config(&config, Some(&rotor_file), &[&plugboard_file], "abc")
+                .unwrap()
+                .encode_message(plaintext)
+                .unwrap(),
+            expected.encode_message(plaintext).unwrap()
+        );
 
-    let mut generated_something = false;
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 
-    if matches.get_flag("generate") {
-        let rotor_file = matches.get_one::<String>("rotor_file").unwrap();
-        if let Err(e) = generate_rotors(rotor_file) {
-            eprintln!("Error generating rotors: {}", e);
-            process::exit(1);
+    #[test]
+    fn switched_plugboard_round_trips_across_the_switch_position() {
+        let pairs = |pairs: &[&str]| {
+            Plugboard::from_pairs(pairs.iter().map(|pair| pair.to_string()).collect()).unwrap()
+        };
+        let machine = || {
+            let mut machine = seeded_machine(12, "abc");
+            machine.plugboard = pairs(&["ab", "cd"]);
+            machine
+                .set_switched_plugboard(pairs(&["ef", "gh", "st"]), 'h')
+                .unwrap();
+            machine
+        };
+        let plaintext = "the plugboard changes midway";
+
+        let ciphertext = machine().encode_message(plaintext).unwrap();
+        assert_eq!(machine().encode_message(&ciphertext).unwrap(), plaintext);
+
+        // The fast rotor reaches 'h' on the seventh keypress.
+        let mut unswitched = seeded_machine(12, "abc");
+        unswitched.plugboard = pairs(&["ab", "cd"]);
+        let unswitched = unswitched.encode_message(plaintext).unwrap();
+        assert_eq!(ciphertext[..6], unswitched[..6]);
+        assert_ne!(ciphertext[6..], unswitched[6..]);
+
+        assert!(
+            machine()
+                .set_switched_plugboard(Plugboard::new(), '0')
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn seeded_generation_produces_exact_wirings() {
+        let dir = scratch_dir("seeded-generation");
+        let path = scratch_file(&dir, "key.enigma");
+
+        let state = generate_rotors(&path, &mut StdRng::seed_from_u64(1), None, 1, false).unwrap();
+
+        assert_eq!(
+            state.rotor1,
+            "JTORVgXdHBvbU wFYxZNMkGDlSjIfiupAPEqosKrzcnmhWyteLQCa"
+        );
+        assert_eq!(
+            state.rotor2,
+            "dwlL QiIfVFJeMRvNyuYUDasOEghzTZSjXPBoxAncWkGrbmpHtCKq"
+        );
+        assert_eq!(
+            state.rotor3,
+            "gMCvSjqmXfTBFQanJNlUEIcKxDwbRohzWLiuOtdpyHsAZYeG kVPr"
+        );
+        assert!(state.reflector.is_none());
+
+        let saved = EnigmaMachine::load_rotor_state(&path).unwrap();
+        assert_eq!(
+            (saved.rotor1, saved.rotor2, saved.rotor3),
+            (state.rotor1, state.rotor2, state.rotor3)
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn indicator_round_trips_and_carries_the_message_key() {
+        let plaintext = "the key travels with the message";
+
+        let ciphertext = seeded_machine(13, "grd")
+            .encode_with_indicator("KeY", plaintext)
+            .unwrap();
+
+        let indicator: String = ciphertext.chars().take(3).collect();
+        assert_eq!(
+            seeded_machine(13, "grd")
+                .encode_message(&indicator)
+                .unwrap(),
+            "KeY"
+        );
+        assert_eq!(
+            ciphertext[3..],
+            seeded_machine(13, "KeY").encode_message(plaintext).unwrap()
+        );
+        assert_eq!(
+            seeded_machine(13, "grd")
+                .decode_with_indicator(&ciphertext)
+                .unwrap(),
+            plaintext
+        );
+        assert!(
+            seeded_machine(13, "grd")
+                .decode_with_indicator("ab")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn overridden_notch_turns_the_neighbour_exactly_there() {
+        let mut machine = seeded_machine(14, "aaa");
+        machine.set_notches([17, 40, 5]).unwrap();
+        assert!(machine.set_notches([ALPHABET.len(), 0, 0]).is_err());
+
+        for press in 1..=ALPHABET.len() {
+            let (_, steps) = machine.encode_char_detailed('x').unwrap();
+            // The fast rotor leaves its notch at 'r' (17) on the 18th keypress.
+            assert_eq!(steps.rotor2, press == 18, "keypress {}", press);
+            assert!(!steps.rotor3, "keypress {}", press);
         }
-        generated_something = true;
+        assert_eq!(machine.positions(), "aba");
     }
 
-    if matches.get_flag("generate_plugboard") {
-        let plugboard_file = matches.get_one::<String>("plugboard_file");
-        let output_file = plugboard_file
-            .map(|s| s.as_str())
-            .unwrap_or(DEFAULT_PLUGBOARD_FILE);
-        if let Err(e) = generate_plugboard(output_file) {
-            eprintln!("Error generating plugboard: {}", e);
-            process::exit(1);
+    #[test]
+    fn explain_describes_positions_notches_reflector_and_plugs() {
+        let mut machine = seeded_machine(15, "xyz");
+        let text = machine.explain();
+        assert!(text.contains("This machine has 3 rotors."), "{}", text);
+        assert!(
+            text.contains("start positions are 'x', 'y', 'z'"),
+            "{}",
+            text
+        );
+        assert!(text.contains("notch letters are 'q', 'e', 'v'"), "{}", text);
+        assert!(
+            text.contains("is set to 'a', and reflects ' ' onto itself"),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains("No plugboard pairs are connected."),
+            "{}",
+            text
+        );
+
+        machine.plugboard =
+            Plugboard::from_pairs(vec!["ab".to_string(), "Cd".to_string()]).unwrap();
+        let text = machine.explain();
+        assert!(
+            text.contains("The plugboard swaps 2 pair(s): 'a' <-> 'b', 'd' <-> 'C'."),
+            "{}",
+            text
+        );
+    }
+
+    #[test]
+    fn seeded_rotors_meet_the_displacement_bound() {
+        assert_eq!(wiring_displacement(ALPHABET), 0);
+        for min_displacement in 1..=5 {
+            let state = random_rotor_state(&mut StdRng::seed_from_u64(16), min_displacement);
+            for wiring in [&state.rotor1, &state.rotor2, &state.rotor3] {
+                assert!(
+                    wiring_displacement(wiring) >= min_displacement,
+                    "{} below {}",
+                    wiring,
+                    min_displacement
+                );
+            }
         }
-        generated_something = true;
     }
 
-    if generated_something {
-        return;
+    #[test]
+    fn message_length_limit_counts_characters() {
+        assert!(check_message_length("hello", 5).is_ok());
+        assert!(check_message_length("", 0).is_ok());
+
+        let error = check_message_length("hello!", 5).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Invalid message: Message is 6 characters, limit is 5"
+        );
     }
 
-    let rotor_file = matches.get_one::<String>("rotor_file").unwrap();
-    let plugboard_file = matches.get_one::<String>("plugboard_file");
+    #[cfg(unix)]
+    #[test]
+    fn output_is_written_to_a_fifo_read_by_another_thread() {
+        let dir = scratch_dir("fifo");
+        let fifo = scratch_file(&dir, "out.fifo");
+        let path = std::ffi::CString::new(fifo.clone()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(path.as_ptr(), 0o600) }, 0);
 
-    let positions = matches.get_one::<String>("positions").unwrap();
-    let message = matches.get_one::<String>("message").unwrap();
+        let reader = {
+            let fifo = fifo.clone();
+            std::thread::spawn(move || std::fs::read_to_string(fifo).unwrap())
+        };
+        let ciphertext = seeded_machine(17, "abc")
+            .encode_message("through a pipe")
+            .unwrap();
+        write_output(&fifo, &ciphertext).unwrap();
 
-    let mut enigma =
-        match EnigmaMachine::new(rotor_file, plugboard_file.map(|s| s.as_str()), positions) {
-            Ok(machine) => machine,
-            Err(e) => {
-                eprintln!("Error initializing Enigma machine: {}", e);
-                process::exit(1);
+        assert_eq!(reader.join().unwrap(), format!("{}\n", ciphertext));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn lint_warns_about_each_weakness() {
+        let clean = || {
+            let state = random_rotor_state(&mut StdRng::seed_from_u64(18), 1);
+            let plugboard = Plugboard::from_pairs(vec!["ab".to_string()]).unwrap();
+            EnigmaMachine::from_parts(state, DEFAULT_NOTCHES, plugboard, "abc").unwrap()
+        };
+        let (warnings, notes) = clean().lint();
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        assert_eq!(notes.len(), 1);
+        assert!(
+            notes[0].starts_with("Reflector maps ' ' to itself"),
+            "{:?}",
+            notes
+        );
+
+        let warned = |machine: EnigmaMachine, expected: &str| {
+            let (warnings, _) = machine.lint();
+            assert_eq!(warnings.len(), 1, "{:?}", warnings);
+            assert!(warnings[0].starts_with(expected), "{:?}", warnings);
+        };
+
+        let mut machine = clean();
+        machine.plugboard = Plugboard::new();
+        warned(machine, "Plugboard is empty");
+
+        let mut machine = clean();
+        machine.rotor2 = Rotor::new(ALPHABET.to_string(), 0).unwrap();
+        warned(machine, "Rotor 2 maps");
+
+        let mut machine = clean();
+        machine.rotor3 = machine.rotor1.clone();
+        warned(machine, "Rotors 1 and 3 share the same wiring");
+
+        let mut machine = clean();
+        machine.set_positions("aaa").unwrap();
+        warned(machine, "Start positions are the default");
+
+        let mut machine = clean();
+        machine.reflector = Reflector {
+            wiring: ALPHABET.to_string(),
+            position: 0,
+        };
+        warned(machine, "Reflector maps");
+    }
+
+    #[test]
+    fn decryptor_decrypts_from_the_start_positions() {
+        let mut machine = seeded_machine(19, "abc");
+        machine.plugboard =
+            Plugboard::from_pairs(vec!["ab".to_string(), "st".to_string()]).unwrap();
+        let plaintext = "decrypt after encrypting";
+
+        let ciphertext = machine.encode_message(plaintext).unwrap();
+        let end = machine.positions();
+
+        let mut decryptor = machine.decryptor("abc").unwrap();
+        assert_eq!(decryptor.encode_message(&ciphertext).unwrap(), plaintext);
+        assert_eq!(machine.positions(), end);
+        assert!(machine.decryptor("ab").is_err());
+    }
+
+    #[test]
+    fn entry_wheel_keeps_the_machine_reciprocal() {
+        let machine = || {
+            let mut machine = seeded_machine(20, "abc");
+            machine.entry_wheel = EntryWheel::from_wiring("(abc)(XYZ )").unwrap();
+            machine
+        };
+        let plaintext = "Entry Wheel";
+
+        let ciphertext = machine().encode_message(plaintext).unwrap();
+        assert_eq!(machine().encode_message(&ciphertext).unwrap(), plaintext);
+        assert_ne!(
+            ciphertext,
+            seeded_machine(20, "abc").encode_message(plaintext).unwrap()
+        );
+
+        assert!(EntryWheel::from_wiring("abc").is_err());
+        assert!(EntryWheel::from_wiring(&ALPHABET.replace('a', "b")).is_err());
+    }
+
+    #[test]
+    fn one_plugs_argument_connects_several_pairs() {
+        let pairs = split_plugs("  ab cD\tXy ");
+        assert_eq!(pairs, ["ab", "cD", "Xy"]);
+
+        let plugboard = Plugboard::from_pairs(pairs).unwrap();
+        for (a, b) in [('a', 'b'), ('c', 'D'), ('X', 'y')] {
+            assert_eq!(plugboard.swap(a), b);
+            assert_eq!(plugboard.swap(b), a);
+        }
+        assert_eq!(plugboard.swap('e'), 'e');
+
+        assert!(Plugboard::from_pairs(split_plugs("ab bc")).is_err());
+    }
+
+    #[test]
+    fn loaded_reflector_is_reciprocal_and_needs_every_pair() {
+        let dir = scratch_dir("reflector-file");
+        let path = scratch_file(&dir, "reflector.toml");
+        let pairs = random_pairs(&mut StdRng::seed_from_u64(21), ALPHABET.len() / 2);
+        write(&path, PlugboardConfig { pairs }.to_toml()).unwrap();
+
+        let reflector = EnigmaMachine::load_reflector(&path).unwrap();
+        let fixed_points = (0..ALPHABET.len())
+            .inspect(|&i| assert_eq!(reflector.reflect(reflector.reflect(i)), i))
+            .filter(|&i| reflector.reflect(i) == i)
+            .count();
+        assert_eq!(fixed_points, 1);
+        assert_eq!(
+            Reflector::from_wiring(&reflector.wiring).unwrap().wiring,
+            reflector.wiring
+        );
+
+        let incomplete = random_pairs(&mut StdRng::seed_from_u64(21), ALPHABET.len() / 2 - 1);
+        write(&path, PlugboardConfig { pairs: incomplete }.to_toml()).unwrap();
+        assert!(EnigmaMachine::load_reflector(&path).is_err());
+
+        let mut one_way: Vec<char> = ALPHABET.chars().collect();
+        one_way.swap(0, 1);
+        one_way.swap(1, 2);
+        assert!(Reflector::from_wiring(&one_way.into_iter().collect::<String>()).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn roundtrip_check_catches_a_broken_reflector() {
+        assert_eq!(
+            seeded_machine(22, "abc")
+                .check_roundtrip("round trip")
+                .unwrap(),
+            None
+        );
+
+        // Shifting every contact by one is a permutation but not a pairing,
+        // so the machine is no longer reciprocal.
+        let mut machine = seeded_machine(22, "abc");
+        let wiring = &machine.reflector.wiring;
+        machine.reflector.wiring = format!("{}{}", &wiring[1..], &wiring[..1]);
+        assert!(machine.check_roundtrip("round trip").unwrap().is_some());
+    }
+
+    #[test]
+    fn reflector_fixed_point_can_be_chosen() {
+        for fixed in ['a', 'M', 'z', ' '] {
+            let reflector = Reflector::with_fixed_point(fixed).unwrap();
+            for (i, c) in ALPHABET.chars().enumerate() {
+                let reflected = reflector.reflect(i);
+                assert_eq!(reflector.reflect(reflected), i);
+                assert_eq!(reflected == i, c == fixed, "{:?} with fixed {:?}", c, fixed);
             }
+        }
+
+        assert!(Reflector::with_fixed_point('0').is_err());
+    }
+
+    #[test]
+    fn restoring_a_snapshot_reproduces_the_encoding() {
+        let mut machine = seeded_machine(23, "abc");
+        machine.set_notches([3, 4, 5]).unwrap();
+        machine.reflector.set_position('k').unwrap();
+        machine.plugboard =
+            Plugboard::from_pairs(vec!["ab".to_string(), "Zz".to_string()]).unwrap();
+        let state = machine.snapshot();
+        let expected = machine.clone().encode_message("from the snapshot").unwrap();
+
+        machine.advance(500);
+        machine.set_notches([0, 0, 0]).unwrap();
+        machine.reflector.set_position('a').unwrap();
+        machine.plugboard = Plugboard::new();
+        machine.restore(&state).unwrap();
+
+        assert_eq!(
+            machine.encode_message("from the snapshot").unwrap(),
+            expected
+        );
+
+        let mut invalid = machine.snapshot();
+        invalid.positions = "a0c".to_string();
+        let before = machine.positions();
+        assert!(machine.restore(&invalid).is_err());
+        assert_eq!(machine.positions(), before);
+    }
+
+    #[test]
+    fn detailed_encoding_reports_the_stepping_schedule() {
+        let mut machine = seeded_machine(24, "Yac");
+        machine.set_notches([51, 1, 40]).unwrap();
+        let mut plain = machine.clone();
+        let step = |rotor1, rotor2, rotor3| StepInfo {
+            rotor1,
+            rotor2,
+            rotor3,
         };
 
-    match enigma.encode_message(message) {
-        Ok(result) => println!("{}", result),
-        Err(e) => {
-            eprintln!("Error encoding message: {}", e);
-            process::exit(1);
+        // The fast rotor leaves its notch 'Z' on the second keypress and
+        // moves the middle rotor onto its notch 'b', so the middle rotor
+        // steps again, with the left rotor, on the third.
+        let schedule = [
+            step(true, false, false),
+            step(true, true, false),
+            step(true, true, true),
+            step(true, false, false),
+        ];
+        for (press, expected) in schedule.into_iter().enumerate() {
+            let (encoded, steps) = machine.encode_char_detailed('e').unwrap();
+            assert_eq!(steps, expected, "keypress {}", press + 1);
+            assert_eq!(encoded, plain.encode_char('e').unwrap());
         }
+        assert_eq!(machine.positions(), "bcd");
+    }
+
+    #[test]
+    fn filters_prepare_mixed_input() {
+        let text = "Hello, World! 42 times";
+
+        assert_eq!(apply_filter(text, "letters"), "HelloWorldtimes");
+        assert_eq!(apply_filter(text, "letters-spaces"), "Hello World  times");
+        assert_eq!(apply_filter(text, "uppercase"), "HELLO, WORLD! 42 TIMES");
+    }
+
+    #[test]
+    fn one_way_cipher_round_trips_only_in_decrypt_mode() {
+        let machine = || {
+            let mut machine = seeded_machine(25, "abc");
+            machine.plugboard = Plugboard::from_pairs(vec!["ab".to_string()]).unwrap();
+            machine
+        };
+        let plaintext = "without a reflector";
+
+        let ciphertext = machine().encode_message_one_way(plaintext, false).unwrap();
+        assert_ne!(ciphertext, plaintext);
+        assert_eq!(
+            machine().encode_message_one_way(&ciphertext, true).unwrap(),
+            plaintext
+        );
+        assert_ne!(
+            machine()
+                .encode_message_one_way(&ciphertext, false)
+                .unwrap(),
+            plaintext
+        );
+        assert!(machine().encode_message_one_way("", false).is_err());
+    }
+
+    #[test]
+    fn spelled_numbers_round_trip_with_a_literal_escape() {
+        let text = "Q branch at 0930 on QUAY 7";
+        let spelled = spell_numbers(text);
+        assert_eq!(
+            spelled,
+            "QQ branch at QZEROQNINEQTHREEQZERO on QQUAY QSEVEN"
+        );
+        assert!(spelled.chars().all(|c| ALPHABET.contains(c)));
+        assert_eq!(unspell_numbers(&spelled).unwrap(), text);
+
+        let ciphertext = seeded_machine(26, "abc").encode_message(&spelled).unwrap();
+        let decrypted = seeded_machine(26, "abc")
+            .encode_message(&ciphertext)
+            .unwrap();
+        assert_eq!(unspell_numbers(&decrypted).unwrap(), text);
+
+        assert!(unspell_numbers("QX").is_err());
+    }
+
+    #[test]
+    fn setting_one_rotor_position_leaves_the_others() {
+        let mut machine = seeded_machine(27, "abc");
+
+        machine.set_rotor_position(2, 'Q').unwrap();
+        assert_eq!(machine.positions(), "aQc");
+        machine.set_rotor_position(1, ' ').unwrap();
+        assert_eq!(machine.positions(), " Qc");
+
+        assert!(machine.set_rotor_position(0, 'a').is_err());
+        assert!(machine.set_rotor_position(4, 'a').is_err());
+        assert!(machine.set_rotor_position(3, '0').is_err());
+        assert_eq!(machine.positions(), " Qc");
+    }
+
+    #[test]
+    fn manifest_describes_the_configuration_without_the_message() {
+        let mut machine = seeded_machine(28, "abc");
+        machine.plugboard =
+            Plugboard::from_pairs(vec!["ab".to_string(), "xy".to_string()]).unwrap();
+        machine.reflector.set_position('k').unwrap();
+        let state = seeded_rotor_state(28);
+        let wirings = [state.rotor1, state.rotor2, state.rotor3].concat();
+
+        let manifest = machine.manifest("abc", &["indicator", "layered"]);
+
+        assert_eq!(
+            manifest,
+            format!(
+                "{{\"rotor_fingerprint\":\"{:016x}\",\"start_positions\":\"abc\",\
+                 \"notches\":\"qev\",\"plug_pairs\":[\"ab\",\"xy\"],\
+                 \"reflector_fingerprint\":\"{:016x}\",\"reflector_position\":\"k\",\
+                 \"entry_wheel_fingerprint\":\"{:016x}\",\"modes\":[\"indicator\",\"layered\"]}}",
+                fnv1a(wirings.as_bytes()),
+                fnv1a(Reflector::new().wiring.as_bytes()),
+                fnv1a(ALPHABET.as_bytes())
+            )
+        );
+    }
+
+    #[test]
+    fn in_place_encoding_matches_encode_message() {
+        let plaintext = "encoded in place";
+        let mut buf: Vec<char> = plaintext.chars().collect();
+        let mut machine = seeded_machine(29, "abc");
+        machine.encode_slice_in_place(&mut buf).unwrap();
+
+        let mut reference = seeded_machine(29, "abc");
+        assert_eq!(
+            buf.iter().collect::<String>(),
+            reference.encode_message(plaintext).unwrap()
+        );
+        assert_eq!(machine.positions(), reference.positions());
+
+        // On an invalid character, the characters before it stay encoded.
+        let mut buf: Vec<char> = "ab0cd".chars().collect();
+        assert!(
+            seeded_machine(29, "abc")
+                .encode_slice_in_place(&mut buf)
+                .is_err()
+        );
+        let prefix = seeded_machine(29, "abc").encode_message("ab").unwrap();
+        assert_eq!(buf.iter().collect::<String>(), format!("{}0cd", prefix));
+    }
+
+    #[test]
+    fn entry_only_plugboard_decrypts_with_exit_only() {
+        let machine = |mode| {
+            let mut machine = seeded_machine(30, "abc");
+            machine.plugboard =
+                Plugboard::from_pairs(vec!["ab".to_string(), "et".to_string()]).unwrap();
+            machine.plugboard_mode = mode;
+            machine
+        };
+        let plaintext = "a better test text";
+
+        let ciphertext = machine(PlugboardMode::EntryOnly)
+            .encode_message(plaintext)
+            .unwrap();
+        assert_eq!(
+            machine(PlugboardMode::ExitOnly)
+                .encode_message(&ciphertext)
+                .unwrap(),
+            plaintext
+        );
+        assert_ne!(
+            machine(PlugboardMode::EntryOnly)
+                .encode_message(&ciphertext)
+                .unwrap(),
+            plaintext
+        );
+    }
+
+    #[test]
+    fn reflector_seed_is_reproducible_and_saved_with_the_rotors() {
+        let dir = scratch_dir("reflector-seed");
+        let generate = |name: &str, reflector_seed| {
+            let path = scratch_file(&dir, name);
+            let state = generate_rotors(
+                &path,
+                &mut StdRng::seed_from_u64(1),
+                Some(reflector_seed),
+                1,
+                false,
+            )
+            .unwrap();
+            (path, state.reflector.unwrap())
+        };
+
+        let (path, first) = generate("first.enigma", 7);
+        let (_, again) = generate("again.enigma", 7);
+        let (_, other) = generate("other.enigma", 8);
+        assert_eq!(first, again);
+        assert_ne!(first, other);
+        assert!(Reflector::from_wiring(&first).is_ok());
+
+        let loaded = EnigmaMachine::new(&path, &[], "abc").unwrap();
+        assert_eq!(loaded.reflector.wiring, first);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn skipping_the_first_step_matches_a_step_after_reference() {
+        let plaintext = "stepping after the lamp lights";
+
+        // A reference implementation that lights the lamp first and steps
+        // afterwards, starting across the fast rotor's notch 'q'.
+        let mut reference = seeded_machine(31, "pYa");
+        let expected: String = plaintext
+            .chars()
+            .map(|c| {
+                let encoded = reference.substitute(c);
+                reference.step_rotors();
+                encoded
+            })
+            .collect();
+
+        let mut machine = seeded_machine(31, "pYa");
+        machine.skip_first_step = true;
+        assert_eq!(machine.encode_message(plaintext).unwrap(), expected);
+
+        // Setting the positions again re-arms the skipped step.
+        machine.set_positions("pYa").unwrap();
+        assert_eq!(machine.encode_message(plaintext).unwrap(), expected);
+        assert_ne!(
+            seeded_machine(31, "pYa").encode_message(plaintext).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn presses_to_next_turnover_counts_to_the_middle_rotor_step() {
+        // Default notches "qev": the fast rotor leaves 'q' (16) after 17
+        // presses from 'a'.
+        assert_eq!(seeded_machine(32, "aaa").presses_to_next_turnover(), 17);
+        assert_eq!(seeded_machine(32, "qaa").presses_to_next_turnover(), 1);
+        assert_eq!(seeded_machine(32, "raa").presses_to_next_turnover(), 53);
+
+        // A middle rotor on its notch 'e' double-steps on the next press.
+        assert_eq!(seeded_machine(32, "aea").presses_to_next_turnover(), 1);
+
+        let mut machine = seeded_machine(32, "aaa");
+        machine.skip_first_step = true;
+        assert_eq!(machine.presses_to_next_turnover(), 18);
+        assert_eq!(machine.positions(), "aaa");
     }
 }
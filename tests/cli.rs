@@ -0,0 +1,676 @@
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+/// Creates an empty scratch directory for one test.
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("enigma-cli-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Runs the binary in `dir` without the path environment variables.
+fn enigma(dir: &PathBuf, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_enigma"))
+        .current_dir(dir)
+        .env_remove("ENIGMA_ROTOR_FILE")
+        .env_remove("ENIGMA_PLUGBOARD_FILE")
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+/// Returns what a successful run printed, without the final newline.
+fn printed(output: Output) -> String {
+    assert!(output.status.success(), "{:?}", output);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    stdout.strip_suffix('\n').unwrap_or(&stdout).to_string()
+}
+
+#[test]
+fn dry_run_generate_reports_the_path_without_writing() {
+    let dir = scratch_dir("dry-run");
+
+    let output = enigma(&dir, &["--dry-run", "--generate", "-r", "key.enigma"]);
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("Would create: key.enigma"), "{}", stdout);
+    assert!(!dir.join("key.enigma").exists());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn dry_run_encode_writes_no_output_files() {
+    let dir = scratch_dir("dry-run-encode");
+    assert!(
+        enigma(&dir, &["--generate", "-r", "key.enigma", "--seed", "1"])
+            .status
+            .success()
+    );
+
+    let output = enigma(
+        &dir,
+        &[
+            "-r",
+            "key.enigma",
+            "--dry-run",
+            "-w",
+            "out.txt",
+            "--manifest",
+            "manifest.json",
+            "--positions-log",
+            "positions.log",
+            "hello",
+        ],
+    );
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    for file in ["out.txt", "manifest.json", "positions.log"] {
+        assert!(
+            stdout.contains(&format!("Would create: {}", file)),
+            "{}",
+            stdout
+        );
+        assert!(!dir.join(file).exists(), "{} was written", file);
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn equivalent_to_applies_overrides_to_both_machines() {
+    let dir = scratch_dir("equivalent");
+    assert!(
+        enigma(&dir, &["--generate", "-r", "key.enigma", "--seed", "1"])
+            .status
+            .success()
+    );
+
+    for overrides in [
+        &["--notches", "abc"][..],
+        &["--plugs", "ab cd"],
+        &["--reflector-fixed", "a", "--reflector-position", "c"],
+        &["--etw", "(ab)"],
+    ] {
+        let mut args = vec!["-r", "key.enigma", "--equivalent-to", "key.enigma"];
+        args.extend_from_slice(overrides);
+        args.push("x");
+        let output = enigma(&dir, &args);
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            "equivalent\n",
+            "{:?}",
+            overrides
+        );
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn layered_machines_round_trip_with_overrides() {
+    let dir = scratch_dir("layered");
+    for (file, seed) in [("first.enigma", "1"), ("second.enigma", "2")] {
+        assert!(
+            enigma(&dir, &["--generate", "-r", file, "--seed", seed])
+                .status
+                .success()
+        );
+    }
+    let layered = |extra: &[&str], message: &str| {
+        let mut args = vec![
+            "-r",
+            "first.enigma",
+            "--second-rotor-file",
+            "second.enigma",
+            "--second-positions",
+            "mno",
+            "--plugs",
+            "ab cd",
+            "--notches",
+            "abc",
+        ];
+        args.extend_from_slice(extra);
+        args.push(message);
+        printed(enigma(&dir, &args))
+    };
+
+    let plaintext = "attack at dawn";
+    let ciphertext = layered(&[], plaintext);
+    assert_ne!(ciphertext, plaintext);
+    assert_eq!(layered(&["--decrypt"], &ciphertext), plaintext);
+
+    let plain_second = printed(enigma(
+        &dir,
+        &[
+            "-r",
+            "first.enigma",
+            "--second-rotor-file",
+            "second.enigma",
+            "--second-positions",
+            "mno",
+            plaintext,
+        ],
+    ));
+    assert_ne!(plain_second, ciphertext);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn random_start_varies_the_ciphertext_unless_seeded_from_a_file() {
+    let dir = scratch_dir("random-start");
+    assert!(
+        enigma(&dir, &["--generate", "-r", "key.enigma", "--seed", "1"])
+            .status
+            .success()
+    );
+    std::fs::write(dir.join("shared.txt"), "shared secret\n").unwrap();
+    let run = |args: &[&str]| {
+        let mut all = vec!["-r", "key.enigma", "--indicator"];
+        all.extend_from_slice(args);
+        printed(enigma(&dir, &all))
+    };
+
+    let plaintext = "hello there";
+    let first = run(&["--random-start", plaintext]);
+    let second = run(&["--random-start", plaintext]);
+    assert_ne!(first, second);
+    for ciphertext in [&first, &second] {
+        assert_eq!(run(&["--decrypt", ciphertext]), plaintext);
+    }
+
+    let seeded = run(&["--random-start", "--seed-file", "shared.txt", plaintext]);
+    assert_eq!(
+        run(&["--random-start", "--seed-file", "shared.txt", plaintext]),
+        seeded
+    );
+    assert_eq!(run(&["--decrypt", &seeded]), plaintext);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn seed_file_generates_identical_rotor_files() {
+    let dir = scratch_dir("seed-file");
+    std::fs::write(dir.join("shared.txt"), "agreed in advance\n").unwrap();
+    std::fs::write(dir.join("other.txt"), "something else\n").unwrap();
+
+    for (seed_file, rotor_file) in [
+        ("shared.txt", "first.enigma"),
+        ("shared.txt", "second.enigma"),
+        ("other.txt", "other.enigma"),
+    ] {
+        let output = enigma(
+            &dir,
+            &["--generate", "-r", rotor_file, "--seed-file", seed_file],
+        );
+        assert!(output.status.success(), "{:?}", output);
+    }
+
+    let read = |file: &str| std::fs::read(dir.join(file)).unwrap();
+    assert_eq!(read("first.enigma"), read("second.enigma"));
+    assert_ne!(read("first.enigma"), read("other.enigma"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn positions_log_follows_the_stepping_sequence() {
+    let dir = scratch_dir("positions-log");
+    assert!(
+        enigma(&dir, &["--generate", "-r", "key.enigma", "--seed", "1"])
+            .status
+            .success()
+    );
+
+    let output = enigma(
+        &dir,
+        &[
+            "-r",
+            "key.enigma",
+            "-s",
+            "YAa",
+            "--notches",
+            "ZBz",
+            "--positions-log",
+            "positions.log",
+            "hello",
+        ],
+    );
+    assert!(output.status.success(), "{:?}", output);
+
+    // The fast rotor reaches its notch Z on the second keypress and turns the
+    // middle rotor to its notch B, which double-steps on the third keypress.
+    assert_eq!(
+        std::fs::read_to_string(dir.join("positions.log")).unwrap(),
+        "ZAa\n Ba\naCb\nbCb\ncCb\n"
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn preview_is_a_prefix_of_the_full_ciphertext() {
+    let dir = scratch_dir("preview");
+    assert!(
+        enigma(&dir, &["--generate", "-r", "key.enigma", "--seed", "1"])
+            .status
+            .success()
+    );
+    let encode = |extra: &[&str]| {
+        let mut args = vec!["-r", "key.enigma", "-s", "abc"];
+        args.extend_from_slice(extra);
+        args.push("a longer message to preview");
+        printed(enigma(&dir, &args))
+    };
+
+    let full = encode(&[]);
+    let preview = encode(&["--preview", "7"]);
+    assert_eq!(preview.chars().count(), 7);
+    assert!(full.starts_with(&preview), "{} / {}", preview, full);
+    assert_eq!(encode(&["--preview", "100"]), full);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn printed_wirings_reload_as_the_same_machine() {
+    let dir = scratch_dir("print-wirings");
+    let stdout = printed(enigma(
+        &dir,
+        &[
+            "--generate",
+            "-r",
+            "key.enigma",
+            "--seed",
+            "1",
+            "--reflector-seed",
+            "3",
+            "--print-wirings",
+        ],
+    ));
+    let wirings: String = stdout
+        .lines()
+        .skip_while(|line| !line.starts_with("rotor1"))
+        .map(|line| format!("{}\n", line))
+        .collect();
+    std::fs::write(dir.join("key.toml"), wirings).unwrap();
+
+    let encode = |rotor_file: &str| {
+        printed(enigma(
+            &dir,
+            &["-r", rotor_file, "-s", "abc", "printed wirings"],
+        ))
+    };
+    assert_eq!(encode("key.toml"), encode("key.enigma"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn json_output_carries_the_text_ciphertext_and_positions() {
+    let dir = scratch_dir("json");
+    assert!(
+        enigma(&dir, &["--generate", "-r", "key.enigma", "--seed", "1"])
+            .status
+            .success()
+    );
+    let encode = |extra: &[&str]| {
+        let mut args = vec!["-r", "key.enigma", "-s", "abc"];
+        args.extend_from_slice(extra);
+        args.push("json output");
+        printed(enigma(&dir, &args))
+    };
+
+    let ciphertext = encode(&[]);
+    assert_eq!(
+        encode(&["--output-format", "json"]),
+        format!(
+            "{{\"ciphertext\":\"{}\",\"start_positions\":\"abc\",\"end_positions\":\"lbc\",\"length\":11}}",
+            ciphertext
+        )
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn generate_refuses_to_overwrite_without_force() {
+    let dir = scratch_dir("overwrite");
+    let generate = |seed: &str, force: bool| {
+        let mut args = vec!["--generate", "-r", "key.enigma", "--seed", seed];
+        if force {
+            args.push("--force");
+        }
+        enigma(&dir, &args)
+    };
+    assert!(generate("1", false).status.success());
+    let original = std::fs::read(dir.join("key.enigma")).unwrap();
+
+    let refused = generate("2", false);
+    assert!(!refused.status.success());
+    assert!(
+        String::from_utf8(refused.stderr)
+            .unwrap()
+            .contains("already exists; use --force to overwrite it")
+    );
+    assert_eq!(std::fs::read(dir.join("key.enigma")).unwrap(), original);
+
+    assert!(generate("2", true).status.success());
+    assert_ne!(std::fs::read(dir.join("key.enigma")).unwrap(), original);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn printed_seed_reproduces_the_generated_file() {
+    let dir = scratch_dir("printed-seed");
+    let output = enigma(&dir, &["--generate", "-r", "sampled.enigma"]);
+    assert!(output.status.success(), "{:?}", output);
+
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let seed = stderr
+        .split_whitespace()
+        .skip_while(|&word| word != "seed")
+        .nth(1)
+        .unwrap_or_else(|| panic!("no seed in {:?}", stderr));
+    assert!(
+        enigma(&dir, &["--generate", "-r", "again.enigma", "--seed", seed])
+            .status
+            .success()
+    );
+
+    assert_eq!(
+        std::fs::read(dir.join("sampled.enigma")).unwrap(),
+        std::fs::read(dir.join("again.enigma")).unwrap()
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn space_placeholder_round_trips_with_and_without_morse_groups() {
+    let dir = scratch_dir("space-as");
+    assert!(
+        enigma(&dir, &["--generate", "-r", "key.enigma", "--seed", "1"])
+            .status
+            .success()
+    );
+    let run = |extra: &[&str], message: &str| {
+        let mut args = vec!["-r", "key.enigma", "-s", "abf", "--space-as", "_"];
+        args.extend_from_slice(extra);
+        args.push(message);
+        printed(enigma(&dir, &args))
+    };
+    let plaintext = "the quick brown fox jumps over the lazy dog";
+
+    let ciphertext = run(&[], plaintext);
+    assert!(!ciphertext.contains(' '));
+    assert!(ciphertext.contains('_'), "{}", ciphertext);
+    assert_eq!(run(&[], &ciphertext), plaintext.replace(' ', "_"));
+
+    let groups = run(&["--morse-groups"], plaintext);
+    assert_eq!(groups.replace(' ', ""), ciphertext);
+    assert_eq!(
+        run(&["--from-morse-groups"], &groups),
+        plaintext.replace(' ', "_")
+    );
+
+    let rejected = enigma(&dir, &["-r", "key.enigma", "--space-as", "a", plaintext]);
+    assert!(!rejected.status.success());
+    assert!(
+        String::from_utf8(rejected.stderr)
+            .unwrap()
+            .contains("--space-as must be 1 character outside the alphabet")
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn ground_setting_procedure_round_trips() {
+    let dir = scratch_dir("ground-setting");
+    assert!(
+        enigma(&dir, &["--generate", "-r", "key.enigma", "--seed", "1"])
+            .status
+            .success()
+    );
+    let run = |args: &[&str]| {
+        let mut all = vec!["-r", "key.enigma"];
+        all.extend_from_slice(args);
+        printed(enigma(&dir, &all))
+    };
+    let plaintext = "report at dawn";
+
+    // The sender encrypts the chosen message key at the ground setting,
+    // then the message from the message key.
+    let ciphertext = run(&["--ground-setting", "grd", "--message-key", "KeY", plaintext]);
+    let (indicator, body) = ciphertext.split_at(3);
+    assert_eq!(indicator, run(&["-s", "grd", "KeY"]));
+    assert_eq!(body, run(&["-s", "KeY", plaintext]));
+
+    // The receiver recovers the message key from the indicator.
+    assert_eq!(
+        run(&["--ground-setting", "grd", "--decrypt", &ciphertext]),
+        plaintext
+    );
+
+    let random_key = run(&["--ground-setting", "grd", plaintext]);
+    assert_eq!(
+        run(&["--ground-setting", "grd", "--decrypt", &random_key]),
+        plaintext
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn interrupt_flushes_partial_stream_output() {
+    use std::io::{Read, Write};
+    use std::process::Stdio;
+
+    let dir = scratch_dir("interrupt");
+    assert!(
+        enigma(&dir, &["--generate", "-r", "key.enigma", "--seed", "1"])
+            .status
+            .success()
+    );
+    let input = "a line of plaintext that keeps the stream busy\n".repeat(4000);
+    std::fs::write(dir.join("input.txt"), &input).unwrap();
+    let stream = |stdin: Stdio| {
+        Command::new(env!("CARGO_BIN_EXE_enigma"))
+            .current_dir(&dir)
+            .env_remove("ENIGMA_ROTOR_FILE")
+            .env_remove("ENIGMA_PLUGBOARD_FILE")
+            .args(["-r", "key.enigma", "--stream"])
+            .stdin(stdin)
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap()
+    };
+
+    let input_file = std::fs::File::open(dir.join("input.txt")).unwrap();
+    let expected = stream(input_file.into()).wait_with_output().unwrap().stdout;
+
+    let mut child = stream(Stdio::piped());
+    let mut stdin = child.stdin.take().unwrap();
+    // The writer hands stdin back open, so the stream only ends through the
+    // interrupt.
+    let writer = std::thread::spawn(move || {
+        let _ = stdin.write_all(input.as_bytes());
+        stdin
+    });
+    let mut stdout = child.stdout.take().unwrap();
+    let mut output = vec![0; 1];
+    stdout.read_exact(&mut output).unwrap();
+
+    assert_eq!(
+        unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGINT) },
+        0
+    );
+    stdout.read_to_end(&mut output).unwrap();
+    let status = child.wait().unwrap();
+    drop(writer.join().unwrap());
+
+    assert_eq!(status.code(), Some(130));
+    assert!(output.ends_with(b"\n"), "partial line in the output");
+    assert!(expected.starts_with(&output));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn rotor_file_flag_overrides_the_environment() {
+    let dir = scratch_dir("env-precedence");
+    for (file, seed) in [("env.enigma", "1"), ("flag.enigma", "2")] {
+        assert!(
+            enigma(&dir, &["--generate", "-r", file, "--seed", seed])
+                .status
+                .success()
+        );
+    }
+    let with_env = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_enigma"))
+            .current_dir(&dir)
+            .env("ENIGMA_ROTOR_FILE", "env.enigma")
+            .env_remove("ENIGMA_PLUGBOARD_FILE")
+            .args(args)
+            .output()
+            .unwrap()
+    };
+
+    let output = with_env(&["--print-paths", "-r", "flag.enigma", "precedence"]);
+    let stderr = String::from_utf8(output.stderr.clone()).unwrap();
+    assert!(
+        stderr.contains("rotor file: flag.enigma (--rotor-file)"),
+        "{}",
+        stderr
+    );
+    assert_eq!(
+        printed(output),
+        printed(enigma(&dir, &["-r", "flag.enigma", "precedence"]))
+    );
+
+    let output = with_env(&["--print-paths", "precedence"]);
+    let stderr = String::from_utf8(output.stderr.clone()).unwrap();
+    assert!(
+        stderr.contains("rotor file: env.enigma (ENIGMA_ROTOR_FILE)"),
+        "{}",
+        stderr
+    );
+    assert_eq!(
+        printed(output),
+        printed(enigma(&dir, &["-r", "env.enigma", "precedence"]))
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn turnover_report_matches_the_stepping_math() {
+    let dir = scratch_dir("turnovers");
+    assert!(
+        enigma(&dir, &["--generate", "-r", "key.enigma", "--seed", "1"])
+            .status
+            .success()
+    );
+    let message = "x".repeat(200);
+
+    let output = enigma(&dir, &["-r", "key.enigma", "--turnover-report", &message]);
+    assert!(output.status.success(), "{:?}", output);
+
+    // From "aaa" with the default notches "qev", the fast rotor leaves 'q' on
+    // keypresses 17, 70, 123 and 176. The last of these moves the middle
+    // rotor onto 'e', and it leaves 'e' on keypress 177.
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "turnovers rotor1=4 rotor2=1\n"
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn avalanche_changes_only_the_modified_position() {
+    let dir = scratch_dir("avalanche");
+    assert!(
+        enigma(&dir, &["--generate", "-r", "key.enigma", "--seed", "1"])
+            .status
+            .success()
+    );
+
+    let report = printed(enigma(
+        &dir,
+        &["-r", "key.enigma", "--avalanche", "3", "hello world"],
+    ));
+    let lines: Vec<&str> = report.lines().collect();
+
+    assert_eq!(lines.len(), 3, "{}", report);
+    assert_eq!(
+        lines[0],
+        printed(enigma(&dir, &["-r", "key.enigma", "hello world"]))
+    );
+    assert_eq!(
+        lines[1],
+        printed(enigma(&dir, &["-r", "key.enigma", "helmo world"]))
+    );
+    assert_eq!(lines[2], "1 of 11 output characters differ, from index 3");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn exported_vectors_reproduce_their_ciphertext() {
+    let dir = scratch_dir("vectors");
+    assert!(
+        enigma(&dir, &["--generate", "-r", "key.enigma", "--seed", "1"])
+            .status
+            .success()
+    );
+    let output = enigma(
+        &dir,
+        &[
+            "-r",
+            "key.enigma",
+            "--seed",
+            "5",
+            "--export-vectors-csv",
+            "vectors.csv",
+            "test vector",
+        ],
+    );
+    assert!(output.status.success(), "{:?}", output);
+
+    let csv = std::fs::read_to_string(dir.join("vectors.csv")).unwrap();
+    let mut rows = csv.lines();
+    assert_eq!(rows.next(), Some("positions,plaintext,ciphertext"));
+    let mut count = 0;
+    for row in rows {
+        // No alphabet character needs escaping, so every field is a plain
+        // quoted string.
+        let fields: Vec<&str> = row
+            .split(',')
+            .map(|field| field.strip_prefix('"').unwrap().strip_suffix('"').unwrap())
+            .collect();
+        assert_eq!(fields.len(), 3, "{}", row);
+        assert_eq!(fields[1], "test vector");
+        assert_eq!(
+            printed(enigma(
+                &dir,
+                &["-r", "key.enigma", "-s", fields[0], fields[1]]
+            )),
+            fields[2],
+            "{}",
+            row
+        );
+        count += 1;
+    }
+    assert_eq!(count, 32);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}